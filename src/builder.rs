@@ -1,14 +1,27 @@
 use std::{
     fs::{self, File},
-    sync::{Mutex, RwLock},
+    io::BufWriter,
+    mem,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
-use chrono::Utc;
+use chrono::{
+    DateTime, Datelike, Days, FixedOffset, Local, Months, NaiveDate, NaiveDateTime, TimeZone,
+    Timelike, Utc,
+};
 use log::Level as LogLevel;
 
 use crate::{
-    logger::{FileHandle, Logger},
+    compressor::Compressor,
+    format::Formatter,
+    logger::{
+        DateDirs, FileHandle, FileOptions, FlushPolicy, LogWriter, LoggerGuard, Retention,
+        RotatingLogger, Writer,
+    },
+    naming::Naming,
     utils::*,
+    worker::Worker,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -22,6 +35,78 @@ pub enum RotationTime {
     Never,
 }
 
+/// Timezone used for line timestamps, rolled file names and rotation boundaries.
+#[derive(Debug, Clone, Copy)]
+pub enum Timezone {
+    Utc,
+    Local,
+    /// Fixed offset east of UTC, in seconds.
+    FixedOffset(i32),
+}
+
+/// What a non-blocking logger does with a record when its queue is full.
+#[derive(Debug, Clone, Copy)]
+pub enum Backpressure {
+    /// Wait until the background writer makes room.
+    Block,
+    /// Drop the record being logged.
+    DropNewest,
+    /// Drop the oldest queued record to make room.
+    DropOldest,
+    /// Drop records less severe than the given level, block for the others.
+    DropBelow(LogLevel),
+}
+
+/// How hard the logger tries to get records onto disk, each level including the previous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Durability {
+    /// Leave writing to disk to the OS.
+    None,
+    /// fsync the log file, the rolled file and their directory when rotating.
+    OnRotation,
+    /// Also fsync after every error level record.
+    OnError,
+    /// fsync after every write.
+    Always,
+}
+
+/// Codec rolled files are compressed with, each adding its own file extension.
+///
+/// The codecs other than gzip are behind cargo features of the same name, so matches on
+/// this enum outside the crate need a wildcard arm.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Rolled files are not compressed.
+    #[default]
+    None,
+    /// gzip with a level from 0 to 9, `.gz`.
+    Gzip(u32),
+    /// zstd with a level from 1 to 22, `.zst`.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// xz with a preset from 0 to 9, `.xz`.
+    #[cfg(feature = "xz")]
+    Xz(u32),
+    /// bzip2 with a level from 1 to 9, `.bz2`.
+    #[cfg(feature = "bzip2")]
+    Bzip2(u32),
+}
+
+/// Where the age of a rolled file is taken from for `max_age`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AgeSource {
+    /// The rotation time encoded in the file name, falling back to the modification time
+    /// for names without one, e.g. with `Naming::Numbered`.
+    #[default]
+    FileName,
+    /// The last modification time of the file.
+    Modified,
+    /// The creation time of the file, falling back to the modification time on
+    /// filesystems that do not record it.
+    Created,
+}
+
 #[derive(Debug)]
 pub enum RotationPolicy {
     MaxSizeOnly(Size),
@@ -30,10 +115,21 @@ pub enum RotationPolicy {
     RotationTimeOnly(RotationTime),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum RotationRemove {
     ByMaxAge(FileAge),
     ByCount(u32),
+    ByTotalSize(Size),
+}
+
+impl RotationRemove {
+    // add the rule to the rules, replacing the rule of the same kind
+    fn add_to(self, mut rules: Vec<RotationRemove>) -> Vec<RotationRemove> {
+        rules.retain(|rule| mem::discriminant(rule) != mem::discriminant(&self));
+        rules.push(self);
+        rules
+    }
 }
 
 pub struct NoFilePath;
@@ -47,32 +143,155 @@ pub struct Builder<T, U, V> {
     pub(crate) rotation_time: RotationTime,
     pub(crate) max_size: U,
     pub(crate) min_size: V,
-    pub(crate) compress: bool,
+    pub(crate) compression: Compression,
     pub(crate) delay_compress: bool,
-    pub(crate) rotation_remove: RotationRemove,
+    pub(crate) rotation_remove: Vec<RotationRemove>,
+    pub(crate) min_keep: u32,
+    pub(crate) age_source: AgeSource,
+    pub(crate) timezone: Timezone,
+    pub(crate) formatter: Box<dyn Formatter>,
+    pub(crate) non_blocking: Option<usize>,
+    pub(crate) backpressure: Backpressure,
+    pub(crate) buffer_size: Option<usize>,
+    pub(crate) flush_level: Option<LogLevel>,
+    pub(crate) flush_interval: Option<Duration>,
+    pub(crate) durability: Durability,
+    pub(crate) copytruncate: bool,
+    pub(crate) naming: Naming,
+    pub(crate) olddir: Option<String>,
+    pub(crate) date_dirs: Option<String>,
 }
 
-impl RotationTime {
-    pub(crate) fn next_rotation_time(&self) -> i64 {
-        let curr_ts = Utc::now().timestamp_millis();
+impl Timezone {
+    pub(crate) fn now(&self) -> DateTime<FixedOffset> {
+        match self {
+            Self::Utc => Utc::now().fixed_offset(),
+            Self::Local => Local::now().fixed_offset(),
+            Self::FixedOffset(secs) => Utc::now().with_timezone(&fixed_offset(*secs)),
+        }
+    }
+
+    // the given time in this timezone
+    pub(crate) fn at(&self, time: SystemTime) -> DateTime<FixedOffset> {
+        let utc = DateTime::<Utc>::from(time);
+        match self {
+            Self::Utc => utc.fixed_offset(),
+            Self::Local => utc.with_timezone(&Local).fixed_offset(),
+            Self::FixedOffset(secs) => utc.with_timezone(&fixed_offset(*secs)),
+        }
+    }
+
+    // unix timestamp in millis of a wall clock time of this timezone
+    pub(crate) fn timestamp_millis(&self, local: &NaiveDateTime) -> i64 {
+        match self {
+            Self::Utc => local_timestamp_millis(&Utc, local),
+            Self::Local => local_timestamp_millis(&Local, local),
+            Self::FixedOffset(secs) => local_timestamp_millis(&fixed_offset(*secs), local),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Self::FixedOffset(secs) = self {
+            if FixedOffset::east_opt(*secs).is_none() {
+                return Err(format!("invalid timezone offset: {} seconds", secs).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fixed_offset(secs: i32) -> FixedOffset {
+    FixedOffset::east_opt(secs).unwrap_or_else(|| FixedOffset::east_opt(0).unwrap())
+}
+
+impl Compression {
+    // extension added to the name of compressed files, none when not compressing
+    pub(crate) fn extension(&self) -> Option<&'static str> {
         match self {
-            Self::Minutely => curr_ts + MIN_AS_MILLI_SEC,
-            Self::Hourly => curr_ts + HOUR_AS_MILLI_SEC,
-            Self::Daily => curr_ts + DAY_AS_MILLI_SEC,
-            Self::Weekly => curr_ts + WEEK_AS_MILLI_SEC,
-            Self::Monthly => curr_ts + MONTH_AS_MILLI_SEC,
-            Self::Yearly => curr_ts + YEAR_AS_MILLI_SEC,
-            Self::Never => 0,
+            Self::None => None,
+            Self::Gzip(_) => Some("gz"),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(_) => Some("zst"),
+            #[cfg(feature = "xz")]
+            Self::Xz(_) => Some("xz"),
+            #[cfg(feature = "bzip2")]
+            Self::Bzip2(_) => Some("bz2"),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let valid = match *self {
+            Self::None => true,
+            Self::Gzip(level) => level <= 9,
+            #[cfg(feature = "zstd")]
+            Self::Zstd(level) => (1..=22).contains(&level),
+            #[cfg(feature = "xz")]
+            Self::Xz(level) => level <= 9,
+            #[cfg(feature = "bzip2")]
+            Self::Bzip2(level) => (1..=9).contains(&level),
+        };
+        if !valid {
+            return Err(format!("invalid compression level: {:?}", self).into());
         }
+        Ok(())
+    }
+}
+
+impl Durability {
+    pub(crate) fn sync_on_rotation(&self) -> bool {
+        *self >= Self::OnRotation
+    }
+
+    pub(crate) fn sync_on_write(&self, level: Option<LogLevel>) -> bool {
+        match self {
+            Self::Always => true,
+            Self::OnError => level == Some(LogLevel::Error),
+            _ => false,
+        }
+    }
+}
+
+impl RotationTime {
+    pub(crate) fn next_rotation_time(&self, timezone: Timezone) -> i64 {
+        match timezone {
+            Timezone::Utc => self.next_rotation_time_from(&Utc::now()),
+            Timezone::Local => self.next_rotation_time_from(&Local::now()),
+            Timezone::FixedOffset(_) => self.next_rotation_time_from(&timezone.now()),
+        }
+    }
+
+    // next calendar boundary (top of the minute/hour, midnight, Monday, first of month or
+    // Jan 1) strictly after `now`, in the timezone of `now`, as unix timestamp in millis
+    pub(crate) fn next_rotation_time_from<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> i64 {
+        let local = now.naive_local();
+        let date = local.date();
+        let next = match self {
+            Self::Minutely => {
+                date.and_hms_opt(local.hour(), local.minute(), 0).unwrap()
+                    + chrono::Duration::minutes(1)
+            }
+            Self::Hourly => {
+                date.and_hms_opt(local.hour(), 0, 0).unwrap() + chrono::Duration::hours(1)
+            }
+            Self::Daily => start_of_day(date + Days::new(1)),
+            Self::Weekly => {
+                let days = 7 - date.weekday().num_days_from_monday() as u64;
+                start_of_day(date + Days::new(days))
+            }
+            Self::Monthly => start_of_day(date.with_day(1).unwrap() + Months::new(1)),
+            Self::Yearly => start_of_day(NaiveDate::from_ymd_opt(date.year() + 1, 1, 1).unwrap()),
+            Self::Never => return 0,
+        };
+        local_timestamp_millis(&now.timezone(), &next)
     }
 }
 
 impl RotationPolicy {
-    pub(crate) fn next_rotation_time(&self) -> i64 {
+    pub(crate) fn next_rotation_time(&self, timezone: Timezone) -> i64 {
         match self {
-            Self::MaxSizeOrRotationTime(_, rt) => rt.next_rotation_time(),
-            Self::MinSizeAndRotationTime(_, rt) => rt.next_rotation_time(),
-            Self::RotationTimeOnly(rt) => rt.next_rotation_time(),
+            Self::MaxSizeOrRotationTime(_, rt) => rt.next_rotation_time(timezone),
+            Self::MinSizeAndRotationTime(_, rt) => rt.next_rotation_time(timezone),
+            Self::RotationTimeOnly(rt) => rt.next_rotation_time(timezone),
             _ => 0,
         }
     }
@@ -107,42 +326,153 @@ impl<T, U, V> Builder<T, U, V> {
         self.rotation_time = RotationTime::Yearly;
         self
     }
+    /// Compresses rolled files with gzip at the default level, unless another codec has
+    /// been chosen with `compression`; `false` leaves them uncompressed.
     pub fn compress(mut self, compress: bool) -> Self {
-        self.compress = compress;
+        self.compression = match (compress, self.compression) {
+            (false, _) => Compression::None,
+            (true, Compression::None) => Compression::Gzip(DEFAULT_GZIP_LEVEL),
+            (true, compression) => compression,
+        };
         self
     }
+    /// Sets the codec rolled files are compressed with; the codecs other than gzip are
+    /// enabled by the `zstd`, `xz` and `bzip2` features.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+    /// Leaves the newest rolled file uncompressed until the next rotation, like logrotate's
+    /// `delaycompress`. All older rolled files are compressed, including those an earlier
+    /// run left uncompressed.
     pub fn delay_compress(mut self, delay_compress: bool) -> Self {
         self.delay_compress = delay_compress;
         self
     }
+    pub fn timezone(mut self, timezone: Timezone) -> Self {
+        self.timezone = timezone;
+        self
+    }
+    pub fn formatter(mut self, formatter: impl Formatter + 'static) -> Self {
+        self.formatter = Box::new(formatter);
+        self
+    }
+    /// Write records on a background thread. Records are queued in a channel holding at
+    /// most `capacity` records; the file is written and rotated on that thread.
+    pub fn non_blocking(mut self, capacity: usize) -> Self {
+        self.non_blocking = Some(capacity);
+        self
+    }
+    /// Sets what happens when the non-blocking queue is full, `Backpressure::Block` by
+    /// default. The number of dropped records is periodically written into the log.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+    /// Buffer writes in memory instead of flushing the file after every record.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = Some(buffer_size);
+        self
+    }
+    /// With a buffer, flush immediately after records at or above the given level.
+    pub fn flush_on(mut self, log_level: LogLevel) -> Self {
+        self.flush_level = Some(log_level);
+        self
+    }
+    /// With a buffer, flush buffered records at least this often. The interval must not be
+    /// zero.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+    /// Sets when the log file is fsynced, `Durability::None` by default.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+    /// Rotate by copying the log file and truncating it in place, instead of renaming it
+    /// and creating a new one. Use it when other processes keep the log file open.
+    pub fn copytruncate(mut self, copytruncate: bool) -> Self {
+        self.copytruncate = copytruncate;
+        self
+    }
+    /// Sets how rolled files are named, `Naming::Timestamp` by default.
+    pub fn naming(mut self, naming: Naming) -> Self {
+        self.naming = naming;
+        self
+    }
+    /// Moves rolled files into the given directory instead of keeping them next to the log
+    /// file. A relative path is relative to the directory of the log file. The directory
+    /// is created on demand and retention and delayed compression operate on it. It must
+    /// be on the same filesystem as the log file unless `copytruncate` is used.
+    pub fn olddir(mut self, olddir: &str) -> Self {
+        self.olddir = Some(olddir.to_owned());
+        self
+    }
+    /// Places rolled files into subdirectories of the archive directory named after the
+    /// rotation time with the given strftime format, e.g. `%Y/%m/%d`. Retention walks the
+    /// whole tree and removes the directories it leaves empty.
+    pub fn date_dirs(mut self, format: &str) -> Self {
+        self.date_dirs = Some(format.to_owned());
+        self
+    }
 }
 
 impl<T, U, V> Builder<T, U, V> {
     pub fn rotation_count(self, count: u32) -> Builder<T, U, V> {
-        let rotation_remove = RotationRemove::ByCount(count);
+        let rotation_remove = RotationRemove::ByCount(count).add_to(self.rotation_remove);
         Builder {
             log_level: self.log_level,
             file_path: self.file_path,
             rotation_time: self.rotation_time,
             max_size: self.max_size,
             min_size: self.min_size,
-            compress: self.compress,
+            compression: self.compression,
             delay_compress: self.delay_compress,
             rotation_remove,
+            min_keep: self.min_keep,
+            age_source: self.age_source,
+            timezone: self.timezone,
+            formatter: self.formatter,
+            non_blocking: self.non_blocking,
+            backpressure: self.backpressure,
+            buffer_size: self.buffer_size,
+            flush_level: self.flush_level,
+            flush_interval: self.flush_interval,
+            durability: self.durability,
+            copytruncate: self.copytruncate,
+            naming: self.naming,
+            olddir: self.olddir,
+            date_dirs: self.date_dirs,
         }
     }
-    pub fn max_age(self, age: FileAge) -> Builder<T, U, V> {
-        let rotation_remove = RotationRemove::ByMaxAge(age);
-        Builder {
-            log_level: self.log_level,
-            file_path: self.file_path,
-            rotation_time: self.rotation_time,
-            max_size: self.max_size,
-            min_size: self.min_size,
-            compress: self.compress,
-            delay_compress: self.delay_compress,
-            rotation_remove,
-        }
+    /// Removes rolled files older than `days` days.
+    pub fn max_age(self, days: u32) -> Self {
+        self.max_age_duration(max_age(days))
+    }
+    /// Removes rolled files older than `age`, for retention periods shorter than a day,
+    /// e.g. `Duration::from_secs(6 * 3600)` to keep the last six hours.
+    pub fn max_age_duration(mut self, age: Duration) -> Self {
+        self.rotation_remove = RotationRemove::ByMaxAge(age).add_to(self.rotation_remove);
+        self
+    }
+    /// Removes the oldest rolled files until the total size of the rolled files is at most
+    /// `max_size` bytes. It can be combined with `rotation_count` and `max_age`.
+    pub fn max_total_size(mut self, max_size: Size) -> Self {
+        self.rotation_remove = RotationRemove::ByTotalSize(max_size).add_to(self.rotation_remove);
+        self
+    }
+    /// Always keeps the newest `count` rolled files, even when they violate `max_age`,
+    /// `max_total_size` or `rotation_count`. Other rolled files are removed as soon as they
+    /// violate any of those rules.
+    pub fn min_keep(mut self, count: u32) -> Self {
+        self.min_keep = count;
+        self
+    }
+    /// Sets where the age of rolled files is taken from, `AgeSource::FileName` by default.
+    pub fn age_source(mut self, age_source: AgeSource) -> Self {
+        self.age_source = age_source;
+        self
     }
 }
 
@@ -154,9 +484,23 @@ impl<T> Builder<T, NoMaxSize, NoMinSize> {
             rotation_time: self.rotation_time,
             max_size,
             min_size: self.min_size,
-            compress: self.compress,
+            compression: self.compression,
             delay_compress: self.delay_compress,
             rotation_remove: self.rotation_remove,
+            min_keep: self.min_keep,
+            age_source: self.age_source,
+            timezone: self.timezone,
+            formatter: self.formatter,
+            non_blocking: self.non_blocking,
+            backpressure: self.backpressure,
+            buffer_size: self.buffer_size,
+            flush_level: self.flush_level,
+            flush_interval: self.flush_interval,
+            durability: self.durability,
+            copytruncate: self.copytruncate,
+            naming: self.naming,
+            olddir: self.olddir,
+            date_dirs: self.date_dirs,
         }
     }
     pub fn min_size(self, min_size: Size) -> Builder<T, NoMaxSize, Size> {
@@ -166,9 +510,23 @@ impl<T> Builder<T, NoMaxSize, NoMinSize> {
             rotation_time: self.rotation_time,
             max_size: self.max_size,
             min_size,
-            compress: self.compress,
+            compression: self.compression,
             delay_compress: self.delay_compress,
             rotation_remove: self.rotation_remove,
+            min_keep: self.min_keep,
+            age_source: self.age_source,
+            timezone: self.timezone,
+            formatter: self.formatter,
+            non_blocking: self.non_blocking,
+            backpressure: self.backpressure,
+            buffer_size: self.buffer_size,
+            flush_level: self.flush_level,
+            flush_interval: self.flush_interval,
+            durability: self.durability,
+            copytruncate: self.copytruncate,
+            naming: self.naming,
+            olddir: self.olddir,
+            date_dirs: self.date_dirs,
         }
     }
 }
@@ -181,9 +539,23 @@ impl<U, V> Builder<NoFilePath, U, V> {
             rotation_time: self.rotation_time,
             max_size: self.max_size,
             min_size: self.min_size,
-            compress: self.compress,
+            compression: self.compression,
             delay_compress: self.delay_compress,
             rotation_remove: self.rotation_remove,
+            min_keep: self.min_keep,
+            age_source: self.age_source,
+            timezone: self.timezone,
+            formatter: self.formatter,
+            non_blocking: self.non_blocking,
+            backpressure: self.backpressure,
+            buffer_size: self.buffer_size,
+            flush_level: self.flush_level,
+            flush_interval: self.flush_interval,
+            durability: self.durability,
+            copytruncate: self.copytruncate,
+            naming: self.naming,
+            olddir: self.olddir,
+            date_dirs: self.date_dirs,
         }
     }
 }
@@ -205,7 +577,7 @@ impl<U: 'static, V: 'static> Builder<String, U, V> {
         }
     }
 
-    pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn build(self) -> Result<RotatingLogger, Box<dyn std::error::Error>> {
         if self.file_path.is_empty() {
             return Err("file_path cannot be empty".into());
         }
@@ -214,28 +586,127 @@ impl<U: 'static, V: 'static> Builder<String, U, V> {
         if file_name.is_empty() {
             return Err("log_file_name cannot be empty".into());
         }
+        self.timezone.validate()?;
+        self.compression.validate()?;
+        let naming = self.naming.scheme(&file_name, &file_extn)?;
+        if self.flush_interval == Some(Duration::ZERO) {
+            return Err("flush_interval cannot be zero".into());
+        }
+        if matches!(&self.olddir, Some(olddir) if olddir.is_empty()) {
+            return Err("olddir cannot be empty".into());
+        }
+        let date_dirs = match &self.date_dirs {
+            Some(format) => Some(DateDirs {
+                format: format.clone(),
+                depth: date_dirs_depth(format)?,
+            }),
+            None => None,
+        };
         if let Some(parent) = file_path.parent() {
             fs::create_dir_all(parent)?;
         }
-        let next_rotation_time = RwLock::new(self.rotation_time.next_rotation_time());
+        let next_rotation_time = self.rotation_time.next_rotation_time(self.timezone);
+        let next_rotation_time = RwLock::new(next_rotation_time);
         let file = File::options()
             .create(true)
             .append(true)
             .open(&self.file_path)?;
         let size = file.metadata()?.len();
-        let file_handle = FileHandle::new(file, size, dir, file_name, file_extn);
+        let file = BufWriter::with_capacity(self.buffer_size.unwrap_or(0), file);
+        let file_handle = FileHandle::new(
+            file,
+            size,
+            dir,
+            file_name,
+            file_extn,
+            FileOptions {
+                timezone: self.timezone,
+                durability: self.durability,
+                copytruncate: self.copytruncate,
+                naming,
+                olddir: self.olddir.clone(),
+                date_dirs,
+            },
+        );
+        let rotation_policy = self.rotation_policy();
+        let retention = Retention {
+            rules: if self.rotation_remove.is_empty() {
+                vec![RotationRemove::ByCount(0)]
+            } else {
+                self.rotation_remove
+            },
+            min_keep: self.min_keep as usize,
+            age_source: self.age_source,
+        };
+        // clean up after a crash before the compressor starts working on the rolled files,
+        // which compresses those left plain; failing to do so must not keep the logger from
+        // starting, the files are retried on the next start
+        for recovered in [
+            file_handle.files.recover_staged(),
+            file_handle.files.recover_compression(),
+        ] {
+            if let Err(e) = recovered {
+                eprintln!("{}", e);
+            }
+        }
+        let compressor = match self.compression {
+            Compression::None => None,
+            compression => Some(Compressor::spawn(
+                file_handle.files.clone(),
+                compression,
+                self.delay_compress,
+                retention.clone(),
+            )?),
+        };
         let file_handle = Mutex::new(file_handle);
-        let logger = Logger {
-            log_level: self.log_level,
+        let log_writer = LogWriter {
             file_handle,
-            rotation_policy: self.rotation_policy(),
+            rotation_policy,
             next_rotation_time,
-            compress: self.compress,
-            delay_compress: self.delay_compress,
-            rotation_remove: self.rotation_remove,
+            retention,
+            compressor,
+            timezone: self.timezone,
+            flush_policy: FlushPolicy {
+                buffered: self.buffer_size.is_some(),
+                level: self.flush_level,
+                interval: self.flush_interval,
+            },
         };
-        log::set_max_level(self.log_level.to_level_filter());
+        let formatter: Arc<dyn Formatter> = Arc::from(self.formatter);
+        let writer = match self.non_blocking {
+            Some(capacity) => {
+                let worker = Worker::spawn(
+                    log_writer,
+                    capacity,
+                    self.backpressure,
+                    formatter.clone(),
+                    self.timezone,
+                )?;
+                Writer::NonBlocking(Arc::new(worker))
+            }
+            None => {
+                let log_writer = Arc::new(log_writer);
+                LogWriter::spawn_flusher(&log_writer)?;
+                Writer::Blocking(log_writer)
+            }
+        };
+        let logger = RotatingLogger {
+            log_level: self.log_level,
+            timezone: self.timezone,
+            formatter,
+            writer,
+        };
+        Ok(logger)
+    }
+
+    /// Builds the logger and installs it as the global logger. The returned guard flushes
+    /// the logger when dropped and must be kept alive in non-blocking mode.
+    pub fn finish(self) -> Result<LoggerGuard, Box<dyn std::error::Error>> {
+        let log_level = self.log_level;
+        let logger = self.build()?;
+        let guard = logger.guard();
         log::set_boxed_logger(Box::new(logger))?;
-        Ok(())
+        log::set_max_level(log_level.to_level_filter());
+        Ok(guard)
     }
 }
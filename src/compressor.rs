@@ -0,0 +1,132 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+
+use chrono::{DateTime, FixedOffset};
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::{
+    builder::Compression,
+    logger::{LogFiles, Retention},
+    naming::compress_rolled_files,
+};
+
+enum Task {
+    // a staged log file and the time it was rotated
+    Rolled(PathBuf, DateTime<FixedOffset>),
+    Wait(Sender<()>),
+    Shutdown,
+}
+
+// background thread naming, compressing and cleaning up rolled files, so that logging
+// threads never wait for compression. Rotation only moves the log file to a staging path;
+// renaming it into the rolled files, which shifts numbered files, happens here so that it
+// is never done to a file being compressed. Retention is applied after compression.
+#[derive(Debug)]
+pub(crate) struct Compressor {
+    sender: Sender<Task>,
+    handle: Option<JoinHandle<()>>,
+}
+
+// state owned by the background thread
+struct Context {
+    files: LogFiles,
+    compression: Compression,
+    delay_compress: bool,
+    retention: Retention,
+}
+
+impl Compressor {
+    pub(crate) fn spawn(
+        files: LogFiles,
+        compression: Compression,
+        delay_compress: bool,
+        retention: Retention,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let context = Context {
+            files,
+            compression,
+            delay_compress,
+            retention,
+        };
+        let handle = thread::Builder::new()
+            .name("logrotate-compress".to_string())
+            .spawn(move || context.run(receiver))?;
+        Ok(Self {
+            sender,
+            handle: Some(handle),
+        })
+    }
+
+    // queue a log file that has just been staged, see `FileHandle::stage`
+    pub(crate) fn send(&self, staged_path: PathBuf, now: DateTime<FixedOffset>) -> io::Result<()> {
+        self.sender
+            .send(Task::Rolled(staged_path, now))
+            .map_err(|_| io::Error::other("compressor has stopped"))
+    }
+
+    // block until every file queued before this call has been rolled and compressed
+    pub(crate) fn wait(&self) {
+        let (ack, done) = crossbeam_channel::bounded(1);
+        if self.sender.send(Task::Wait(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
+
+impl Drop for Compressor {
+    // compress the queued files and stop the background thread
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.sender.send(Task::Shutdown);
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Context {
+    fn run(self, receiver: Receiver<Task>) {
+        // files rolled before a restart, or whose compression was interrupted, may have
+        // been left plain
+        if let Err(e) = self.compress_rolled() {
+            eprintln!("{}", e);
+        }
+        for task in receiver {
+            match task {
+                Task::Rolled(staged_path, now) => {
+                    if let Err(e) = self.rolled(&staged_path, &now) {
+                        eprintln!("{}", e);
+                    }
+                }
+                Task::Wait(ack) => {
+                    let _ = ack.send(());
+                }
+                Task::Shutdown => break,
+            }
+        }
+    }
+
+    // give the staged file its rolled name and compress it, or with delayed compression
+    // all the older rolled files, then remove the rolled files violating the retention
+    // rules; retention runs even when compression fails, e.g. on a full disk where it is
+    // needed most
+    fn rolled(&self, staged_path: &Path, now: &DateTime<FixedOffset>) -> io::Result<()> {
+        let compressed = self
+            .files
+            .place_staged(staged_path, now)
+            .and_then(|_| self.compress_rolled());
+        self.files.remove_old_files(&self.retention)?;
+        compressed
+    }
+
+    // compress every plain rolled file of the log, except the newest one with delayed
+    // compression
+    fn compress_rolled(&self) -> io::Result<()> {
+        let files = self.files.rolled_files()?;
+        let skip = if self.delay_compress { 1 } else { 0 };
+        compress_rolled_files(files.get(skip..).unwrap_or_default(), self.compression)
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::utils::TIMESTAMP_FORMAT;
+
+/// Turns a log record into the bytes written to the log file.
+///
+/// `timestamp` is the time of the record in the timezone configured on the builder. Any
+/// `Fn(&mut Vec<u8>, &DateTime<FixedOffset>, &log::Record) -> io::Result<()>` closure can be
+/// used as a formatter.
+pub trait Formatter: Send + Sync {
+    fn format(
+        &self,
+        buf: &mut Vec<u8>,
+        timestamp: &DateTime<FixedOffset>,
+        record: &log::Record,
+    ) -> io::Result<()>;
+}
+
+impl fmt::Debug for dyn Formatter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Formatter")
+    }
+}
+
+impl<F> Formatter for F
+where
+    F: Fn(&mut Vec<u8>, &DateTime<FixedOffset>, &log::Record) -> io::Result<()> + Send + Sync,
+{
+    fn format(
+        &self,
+        buf: &mut Vec<u8>,
+        timestamp: &DateTime<FixedOffset>,
+        record: &log::Record,
+    ) -> io::Result<()> {
+        self(buf, timestamp, record)
+    }
+}
+
+/// The default format: `{timestamp} {file}:{line} [{target}] {level}: {message}`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TextFormatter;
+
+impl Formatter for TextFormatter {
+    fn format(
+        &self,
+        buf: &mut Vec<u8>,
+        timestamp: &DateTime<FixedOffset>,
+        record: &log::Record,
+    ) -> io::Result<()> {
+        let file_line = match (record.file(), record.line()) {
+            (Some(f), Some(l)) => format!("{}:{}", f, l),
+            _ => String::new(),
+        };
+        writeln!(
+            buf,
+            "{} {} [{}] {}: {}",
+            timestamp.format(TIMESTAMP_FORMAT),
+            file_line,
+            record.target(),
+            record.level(),
+            record.args()
+        )
+    }
+}
+
+/// Writes one JSON object per line with the fields `timestamp`, `level`, `target`,
+/// `module_path`, `file`, `line` and `message`. With the `kv` feature enabled the record's
+/// key/value pairs are written as a nested `fields` object.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonFormatter;
+
+impl Formatter for JsonFormatter {
+    fn format(
+        &self,
+        buf: &mut Vec<u8>,
+        timestamp: &DateTime<FixedOffset>,
+        record: &log::Record,
+    ) -> io::Result<()> {
+        buf.extend_from_slice(b"{\"timestamp\":");
+        write_json_str(buf, &timestamp.format(RFC3339_FORMAT).to_string());
+        buf.extend_from_slice(b",\"level\":");
+        write_json_str(buf, record.level().as_str());
+        buf.extend_from_slice(b",\"target\":");
+        write_json_str(buf, record.target());
+        buf.extend_from_slice(b",\"module_path\":");
+        write_json_opt_str(buf, record.module_path());
+        buf.extend_from_slice(b",\"file\":");
+        write_json_opt_str(buf, record.file());
+        buf.extend_from_slice(b",\"line\":");
+        match record.line() {
+            Some(line) => write!(buf, "{}", line)?,
+            None => buf.extend_from_slice(b"null"),
+        }
+        buf.extend_from_slice(b",\"message\":");
+        write_json_str(buf, &record.args().to_string());
+        #[cfg(feature = "kv")]
+        {
+            buf.extend_from_slice(b",\"fields\":{");
+            let mut first = true;
+            for_each_key_value(record, |key, value| {
+                if !first {
+                    buf.push(b',');
+                }
+                first = false;
+                write_json_str(buf, key.as_str());
+                buf.push(b':');
+                write_json_value(buf, &value)
+            })?;
+            buf.push(b'}');
+        }
+        buf.extend_from_slice(b"}\n");
+        Ok(())
+    }
+}
+
+/// Writes records in logfmt, e.g.
+/// `ts=2024-03-02T03:22:36.000+00:00 level=info target=app file=src/main.rs line=3 msg="hello"`.
+/// With the `kv` feature enabled the record's key/value pairs are appended as `key=value`,
+/// with spaces, quotes, `=` and control characters in keys replaced by `_`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogfmtFormatter;
+
+impl Formatter for LogfmtFormatter {
+    fn format(
+        &self,
+        buf: &mut Vec<u8>,
+        timestamp: &DateTime<FixedOffset>,
+        record: &log::Record,
+    ) -> io::Result<()> {
+        write!(buf, "ts={}", timestamp.format(RFC3339_FORMAT))?;
+        write!(buf, " level={}", record.level().as_str().to_lowercase())?;
+        buf.extend_from_slice(b" target=");
+        write_logfmt_value(buf, record.target());
+        if let Some(module_path) = record.module_path() {
+            buf.extend_from_slice(b" module=");
+            write_logfmt_value(buf, module_path);
+        }
+        if let Some(file) = record.file() {
+            buf.extend_from_slice(b" file=");
+            write_logfmt_value(buf, file);
+        }
+        if let Some(line) = record.line() {
+            write!(buf, " line={}", line)?;
+        }
+        buf.extend_from_slice(b" msg=");
+        write_logfmt_value(buf, &record.args().to_string());
+        #[cfg(feature = "kv")]
+        for_each_key_value(record, |key, value| {
+            buf.push(b' ');
+            write_logfmt_key(buf, key.as_str());
+            buf.push(b'=');
+            write_logfmt_value(buf, &value.to_string());
+            Ok(())
+        })?;
+        buf.push(b'\n');
+        Ok(())
+    }
+}
+
+const RFC3339_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+fn write_json_str(buf: &mut Vec<u8>, s: &str) {
+    buf.push(b'"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.extend_from_slice(b"\\\""),
+            '\\' => buf.extend_from_slice(b"\\\\"),
+            '\n' => buf.extend_from_slice(b"\\n"),
+            '\r' => buf.extend_from_slice(b"\\r"),
+            '\t' => buf.extend_from_slice(b"\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => {
+                let mut tmp = [0; 4];
+                buf.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+            }
+        }
+    }
+    buf.push(b'"');
+}
+
+fn write_json_opt_str(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => write_json_str(buf, s),
+        None => buf.extend_from_slice(b"null"),
+    }
+}
+
+// keys cannot be quoted, so spaces, quotes, `=` and control characters in them are replaced
+// with `_`, as is an empty key
+#[cfg(feature = "kv")]
+fn write_logfmt_key(buf: &mut Vec<u8>, key: &str) {
+    if key.is_empty() {
+        buf.push(b'_');
+    }
+    let key: String = key
+        .chars()
+        .map(|c| match c {
+            ' ' | '=' | '"' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    buf.extend_from_slice(key.as_bytes());
+}
+
+// values are written bare unless empty or containing spaces, quotes, `=` or control
+// characters, in which case they are quoted and escaped
+fn write_logfmt_value(buf: &mut Vec<u8>, s: &str) {
+    let needs_quotes = s.is_empty()
+        || s.chars()
+            .any(|c| c == ' ' || c == '=' || c == '"' || c == '\\' || c.is_control());
+    if needs_quotes {
+        write_json_str(buf, s);
+    } else {
+        buf.extend_from_slice(s.as_bytes());
+    }
+}
+
+#[cfg(feature = "kv")]
+fn write_json_value(buf: &mut Vec<u8>, value: &log::kv::Value) -> io::Result<()> {
+    if let Some(v) = value.to_bool() {
+        write!(buf, "{}", v)
+    } else if let Some(v) = value.to_i64() {
+        write!(buf, "{}", v)
+    } else if let Some(v) = value.to_u64() {
+        write!(buf, "{}", v)
+    } else if let Some(v) = value.to_f64().filter(|v| v.is_finite()) {
+        write!(buf, "{}", v)
+    } else {
+        write_json_str(buf, &value.to_string());
+        Ok(())
+    }
+}
+
+// call `f` for every key/value pair of the record, stopping at the first error
+#[cfg(feature = "kv")]
+fn for_each_key_value<F>(record: &log::Record, f: F) -> io::Result<()>
+where
+    F: FnMut(log::kv::Key, log::kv::Value) -> io::Result<()>,
+{
+    struct Visitor<F> {
+        f: F,
+        error: Option<io::Error>,
+    }
+
+    impl<'kvs, F> log::kv::VisitSource<'kvs> for Visitor<F>
+    where
+        F: FnMut(log::kv::Key, log::kv::Value) -> io::Result<()>,
+    {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            (self.f)(key, value).map_err(|e| {
+                self.error = Some(e);
+                log::kv::Error::msg("failed to format key/value pair")
+            })
+        }
+    }
+
+    let mut visitor = Visitor { f, error: None };
+    if let Err(e) = record.key_values().visit(&mut visitor) {
+        return Err(visitor
+            .error
+            .unwrap_or_else(|| io::Error::other(e.to_string())));
+    }
+    Ok(())
+}
@@ -1,5 +1,10 @@
 use builder::*;
 
+pub use builder::{AgeSource, Backpressure, Compression, Durability, Timezone};
+pub use format::{Formatter, JsonFormatter, LogfmtFormatter, TextFormatter};
+pub use logger::{LoggerGuard, RotatingLogger};
+pub use naming::Naming;
+
 pub use log::debug;
 pub use log::error;
 pub use log::info;
@@ -8,8 +13,12 @@ pub use log::warn;
 pub use log::Level;
 
 mod builder;
+mod compressor;
+mod format;
 mod logger;
+mod naming;
 mod utils;
+mod worker;
 
 #[cfg(test)]
 mod tests;
@@ -18,16 +27,29 @@ pub fn builder() -> Builder<NoFilePath, NoMaxSize, NoMinSize> {
     let file_path = NoFilePath;
     let max_size = NoMaxSize;
     let min_size = NoMinSize;
-    let rotation_remove = RotationRemove::ByCount(0);
-    let builder = Builder {
+    let rotation_remove = Vec::new();
+    Builder {
         log_level: log::Level::Trace,
         file_path,
         rotation_time: RotationTime::Never,
         max_size,
         min_size,
-        compress: false,
+        compression: Compression::None,
         delay_compress: false,
         rotation_remove,
-    };
-    builder
+        min_keep: 0,
+        age_source: AgeSource::FileName,
+        timezone: Timezone::Utc,
+        formatter: Box::new(TextFormatter),
+        non_blocking: None,
+        backpressure: Backpressure::Block,
+        buffer_size: None,
+        flush_level: None,
+        flush_interval: None,
+        durability: Durability::None,
+        copytruncate: false,
+        naming: Naming::Timestamp,
+        olddir: None,
+        date_dirs: None,
+    }
 }
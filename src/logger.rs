@@ -1,110 +1,460 @@
 use std::{
     error::Error,
-    fs::File,
-    io::{self, Write},
-    path::PathBuf,
-    sync::{Mutex, RwLock},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, Weak},
+    thread,
+    time::{Duration, Instant},
 };
 
-use chrono::Utc;
+use chrono::{DateTime, FixedOffset};
 use log::Level as LogLevel;
 
 use crate::{
-    builder::{RotationPolicy, RotationRemove},
+    builder::{AgeSource, Compression, Durability, RotationPolicy, RotationRemove, Timezone},
+    compressor::Compressor,
+    format::Formatter,
+    naming::*,
     utils::*,
+    worker::Worker,
 };
 
 #[derive(Debug)]
 pub(crate) struct FileHandle {
-    inner: File,
+    inner: BufWriter<File>,
     size: u64,
+    last_flush: Instant,
+    // sequence number of the last staged log file, see `FileHandle::stage`
+    staged: u64,
+    pub(crate) files: LogFiles,
+}
+
+// where the log file and its rolled files are and how they are named, shared with the
+// compressor thread
+#[derive(Debug, Clone)]
+pub(crate) struct LogFiles {
     dir: String,
     file_name: String,
     file_extn: String,
+    pub(crate) options: FileOptions,
+}
+
+// how the log file is rotated
+#[derive(Debug, Clone)]
+pub(crate) struct FileOptions {
+    pub(crate) timezone: Timezone,
+    pub(crate) durability: Durability,
+    pub(crate) copytruncate: bool,
+    pub(crate) naming: NameScheme,
+    pub(crate) olddir: Option<String>,
+    pub(crate) date_dirs: Option<DateDirs>,
+}
+
+// strftime format of the subdirectories of the archive directory holding rolled files
+#[derive(Debug, Clone)]
+pub(crate) struct DateDirs {
+    pub(crate) format: String,
+    pub(crate) depth: usize,
 }
 
+// writes messages to the log file, rotating it as per the rotation policy
 #[derive(Debug)]
-pub(crate) struct Logger {
-    pub(crate) log_level: LogLevel,
+pub(crate) struct LogWriter {
     pub(crate) file_handle: Mutex<FileHandle>,
     pub(crate) rotation_policy: RotationPolicy,
     pub(crate) next_rotation_time: RwLock<i64>,
-    pub(crate) compress: bool,
-    pub(crate) delay_compress: bool,
-    pub(crate) rotation_remove: RotationRemove,
+    pub(crate) retention: Retention,
+    // compresses rolled files in the background, when compression is enabled
+    pub(crate) compressor: Option<Compressor>,
+    pub(crate) timezone: Timezone,
+    pub(crate) flush_policy: FlushPolicy,
+}
+
+// which rolled files are removed after a rotation
+#[derive(Debug, Clone)]
+pub(crate) struct Retention {
+    pub(crate) rules: Vec<RotationRemove>,
+    pub(crate) min_keep: usize,
+    pub(crate) age_source: AgeSource,
+}
+
+// when buffered records are flushed to the file; unbuffered writes flush every record
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FlushPolicy {
+    pub(crate) buffered: bool,
+    pub(crate) level: Option<LogLevel>,
+    pub(crate) interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Writer {
+    Blocking(Arc<LogWriter>),
+    NonBlocking(Arc<Worker>),
+}
+
+/// A rotating file logger returned by `Builder::build`.
+///
+/// It implements [`log::Log`], so it can be composed with other loggers or installed
+/// globally, and [`std::io::Write`] to write raw bytes into the rotating file.
+#[derive(Debug)]
+pub struct RotatingLogger {
+    pub(crate) log_level: LogLevel,
+    pub(crate) timezone: Timezone,
+    pub(crate) formatter: Arc<dyn Formatter>,
+    pub(crate) writer: Writer,
+}
+
+/// Flushes the logger when dropped, waits for rolled files to be compressed and, in
+/// non-blocking mode, waits for the background writer to write all queued records and
+/// stops it. Keep it alive for as long as the logger is in use.
+#[must_use = "dropping the guard stops the background writer"]
+#[derive(Debug)]
+pub struct LoggerGuard {
+    writer: Writer,
 }
 
 impl FileHandle {
     pub(crate) fn new(
-        inner: File,
+        inner: BufWriter<File>,
         size: u64,
         dir: String,
         file_name: String,
         file_extn: String,
+        options: FileOptions,
     ) -> Self {
         Self {
             inner,
             size,
-            dir,
-            file_name,
-            file_extn,
+            last_flush: Instant::now(),
+            staged: 0,
+            files: LogFiles {
+                dir,
+                file_name,
+                file_extn,
+                options,
+            },
         }
     }
 
-    pub(crate) fn log_path(&self) -> PathBuf {
-        log_file_path(&self.dir, &self.file_name, &self.file_extn)
+    pub(crate) fn write_message(
+        &mut self,
+        level: Option<LogLevel>,
+        message: &[u8],
+    ) -> io::Result<()> {
+        let size = message.len() as u64;
+        self.inner.write_all(message)?;
+        self.size += size;
+        if self.files.options.durability.sync_on_write(level) {
+            self.sync()?;
+        }
+        Ok(())
     }
 
-    pub(crate) fn rolled_log_path(&self, compress: bool) -> PathBuf {
-        rolled_log_path(&self.dir, &self.file_name, &self.file_extn, compress)
+    // flush and fsync the log file
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.inner.get_ref().sync_data()
     }
 
-    pub(crate) fn write_message(&mut self, message: &str) -> io::Result<()> {
-        let size = message.len() as u64;
-        let file = self.inner.by_ref();
-        file.write_all(message.as_bytes())?;
-        file.flush()?;
-        self.size += size;
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.last_flush = Instant::now();
         Ok(())
     }
 
     pub(crate) fn truncate(&mut self) -> io::Result<()> {
-        truncate_file(&mut self.inner)?;
+        self.flush()?;
+        truncate_file(self.inner.get_mut())?;
         self.size = 0;
+        if self.files.options.durability.sync_on_rotation() {
+            self.inner.get_ref().sync_all()?;
+        }
         Ok(())
     }
 
-    pub(crate) fn rollover(&mut self, compress: bool) -> io::Result<()> {
-        let roll_path = self.rolled_log_path(compress);
-        let log_path = self.log_path();
-        let out_file = File::create(&roll_path)?;
-        let mut file = File::open(&log_path)?;
-        copy_file(&mut file, out_file, compress)?;
+    // move the content of the log file into a rolled file and return its path
+    pub(crate) fn rollover(&mut self) -> io::Result<PathBuf> {
+        let now = self.files.options.timezone.now();
+        let roll_path = self.files.rolled_log_path(&now)?;
+        self.move_content(&roll_path)?;
+        Ok(roll_path)
+    }
+
+    // move the content of the log file into a staging file that the compressor thread
+    // gives its rolled name later, so that rotation never waits for rolled files being
+    // renamed or compressed
+    pub(crate) fn stage(&mut self) -> io::Result<PathBuf> {
+        self.staged += 1;
+        let staged_path = self.files.staged_path(self.staged)?;
+        self.move_content(&staged_path)?;
+        Ok(staged_path)
+    }
+
+    // move the content of the log file to the given path, either by renaming the file and
+    // opening a new one or by copying it and truncating the original
+    fn move_content(&mut self, roll_path: &Path) -> io::Result<()> {
+        let sync = self.files.options.durability.sync_on_rotation();
+        if sync {
+            self.sync()?;
+        } else {
+            self.flush()?;
+        }
+        let log_path = self.files.log_path();
+        if self.files.options.copytruncate {
+            let out_file = File::create(roll_path)?;
+            let mut file = File::open(&log_path)?;
+            copy_file(&mut file, out_file, Compression::None)?;
+            if sync {
+                File::open(roll_path)?.sync_all()?;
+            }
+            self.truncate()?;
+        } else {
+            fs::rename(&log_path, roll_path)?;
+            self.reopen()?;
+            if sync {
+                File::open(roll_path)?.sync_all()?;
+            }
+        }
+        if sync {
+            self.files.sync_dirs(roll_path)?;
+        }
         Ok(())
     }
 
-    pub(crate) fn compress_old_files(&self) -> io::Result<()> {
-        compress_old_files(&self.dir, &self.file_name, &self.file_extn)?;
+    // open a new, empty log file in place of the renamed one
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(self.files.log_path())?;
+        self.inner = BufWriter::with_capacity(self.inner.capacity(), file);
+        self.size = 0;
         Ok(())
     }
+}
+
+impl LogFiles {
+    pub(crate) fn log_path(&self) -> PathBuf {
+        log_file_path(&self.dir, &self.file_name, &self.file_extn)
+    }
 
-    pub(crate) fn remove_file_by_count(&self, count: usize) -> io::Result<()> {
-        remove_file_by_count(&self.dir, &self.file_name, &self.file_extn, count)
+    // directory of the rolled files, created on demand when it is not the log directory
+    pub(crate) fn archive_dir(&self) -> io::Result<String> {
+        match &self.options.olddir {
+            Some(olddir) => {
+                let dir = Path::new(&self.dir).join(olddir);
+                fs::create_dir_all(&dir)?;
+                Ok(dir.to_string_lossy().into_owned())
+            }
+            None => Ok(self.dir.clone()),
+        }
     }
 
-    pub(crate) fn remove_files_by_age(&self, age: FileAge) -> io::Result<()> {
-        remove_files_by_age(&self.dir, &self.file_name, &self.file_extn, age)
+    // how many levels of date directories are below the archive directory
+    fn depth(&self) -> usize {
+        self.options.date_dirs.as_ref().map_or(0, |d| d.depth)
+    }
+
+    // path for the next rolled file, making room for it if needed
+    pub(crate) fn rolled_log_path(&self, now: &DateTime<FixedOffset>) -> io::Result<PathBuf> {
+        let root = &self.archive_dir()?;
+        let depth = self.depth();
+        let dir = &match &self.options.date_dirs {
+            Some(date_dirs) => {
+                let dir = Path::new(root).join(now.format(&date_dirs.format).to_string());
+                fs::create_dir_all(&dir)?;
+                dir.to_string_lossy().into_owned()
+            }
+            None => root.clone(),
+        };
+        let (name, extn) = (&self.file_name, &self.file_extn);
+        let naming = &self.options.naming;
+        naming.shift_rolled_files(root, depth, name, extn)?;
+        match naming {
+            NameScheme::Timestamp => {
+                let path = rolled_log_path(dir, name, extn, None, now);
+                let tail = if extn.is_empty() {
+                    String::new()
+                } else {
+                    format!(".{}", extn)
+                };
+                unique_rolled_path(path, &tail)
+            }
+            NameScheme::Numbered => Ok(numbered_path(dir, name, extn, 1, None)),
+            NameScheme::Template(template) => {
+                let date = template.format_date(now);
+                if template.has_index() {
+                    Ok(template.path(dir, &date, 1, None))
+                } else {
+                    unique_rolled_path(template.path(dir, &date, 0, None), "")
+                }
+            }
+        }
+    }
+
+    // path of a log file staged for the compressor thread; it is in the archive directory,
+    // so that giving it its rolled name is a rename on the same filesystem even when the
+    // log file is copied to another one, and never matches a rolled file name
+    pub(crate) fn staged_path(&self, seq: u64) -> io::Result<PathBuf> {
+        let name = log_file_full_name(&self.file_name, &self.file_extn);
+        let file_name = format!("{}.{}{}", name, STAGED_INFIX, seq);
+        Ok(Path::new(&self.archive_dir()?).join(file_name))
+    }
+
+    // give a staged log file its rolled name and return it
+    pub(crate) fn place_staged(
+        &self,
+        staged_path: &Path,
+        now: &DateTime<FixedOffset>,
+    ) -> io::Result<PathBuf> {
+        let roll_path = self.rolled_log_path(now)?;
+        fs::rename(staged_path, &roll_path)?;
+        if self.options.durability.sync_on_rotation() {
+            self.sync_dirs(&roll_path)?;
+        }
+        Ok(roll_path)
+    }
+
+    // fsync the log directory and the directory of the rolled file when it differs
+    fn sync_dirs(&self, roll_path: &Path) -> io::Result<()> {
+        sync_dir(&self.dir)?;
+        let roll_dir = roll_path.parent().unwrap_or(Path::new(""));
+        if roll_dir != Path::new(&self.dir) {
+            sync_dir(&roll_dir.to_string_lossy())?;
+        }
+        Ok(())
+    }
+
+    // give the log files staged before a crash their rolled names, in rotation order
+    pub(crate) fn recover_staged(&self) -> io::Result<()> {
+        let name = log_file_full_name(&self.file_name, &self.file_extn);
+        let prefix = format!("{}.{}", name, STAGED_INFIX);
+        let mut staged = vec![];
+        for entry in read_dir(&self.archive_dir()?)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let seq = name
+                .strip_prefix(&prefix)
+                .and_then(|seq| seq.parse::<u64>().ok());
+            if let Some(seq) = seq.filter(|_| path.is_file()) {
+                staged.push((seq, path));
+            }
+        }
+        staged.sort_unstable();
+        for (_, path) in staged {
+            // a staged file is not written to after the rotation, so it was last modified
+            // when the log was rotated
+            let rotated = fs::metadata(&path)?.modified()?;
+            self.place_staged(&path, &self.options.timezone.at(rotated))?;
+        }
+        Ok(())
+    }
+
+    // rolled files of the log file, ordered from newest to oldest
+    pub(crate) fn rolled_files(&self) -> io::Result<Vec<RolledFile>> {
+        let (dir, depth) = (&self.archive_dir()?, self.depth());
+        let naming = &self.options.naming;
+        naming.rolled_files(dir, depth, &self.file_name, &self.file_extn)
+    }
+
+    // clean up after compressions interrupted by a crash: partial temporary files are
+    // removed, as are plain files whose compressed version is complete; the compressor
+    // compresses the remaining plain files when it starts
+    pub(crate) fn recover_compression(&self) -> io::Result<()> {
+        let (dir, depth) = (&self.archive_dir()?, self.depth());
+        let (naming, name, extn) = (&self.options.naming, &self.file_name, &self.file_extn);
+        for tmp_path in naming.tmp_files(dir, depth, name, extn)? {
+            fs::remove_file(&tmp_path)?;
+        }
+        for file in naming.rolled_files(dir, depth, name, extn)? {
+            if file.compressed.is_some() {
+                continue;
+            }
+            for extn in COMPRESSED_EXTNS {
+                let path = compressed_path(&file.path, extn);
+                if !path.is_file() {
+                    continue;
+                }
+                if is_complete(&path, extn)? {
+                    fs::remove_file(&file.path)?;
+                } else {
+                    fs::remove_file(&path)?;
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // remove rolled files violating any of the retention rules
+    pub(crate) fn remove_old_files(&self, retention: &Retention) -> io::Result<()> {
+        let files = self.rolled_files()?;
+        remove_rolled_files(&files, self.depth(), retention, self.options.timezone)
+    }
+}
+
+impl FlushPolicy {
+    fn should_flush(&self, level: Option<LogLevel>, last_flush: Instant) -> bool {
+        !self.buffered
+            || matches!((level, self.level), (Some(level), Some(flush_level)) if level <= flush_level)
+            || self.is_flush_due(last_flush)
+    }
+
+    fn is_flush_due(&self, last_flush: Instant) -> bool {
+        matches!(self.interval, Some(interval) if last_flush.elapsed() >= interval)
     }
 }
 
-impl Logger {
-    fn write_message(&self, message: &str) -> Result<(), Box<dyn Error + '_>> {
+impl LogWriter {
+    pub(crate) fn write_message(
+        &self,
+        level: Option<LogLevel>,
+        message: &[u8],
+    ) -> Result<(), Box<dyn Error + '_>> {
         self.rotate_log()?;
         let mut handle = self.file_handle.lock()?;
-        handle.write_message(message)?;
+        handle.write_message(level, message)?;
+        if self.flush_policy.should_flush(level, handle.last_flush) {
+            handle.flush()?;
+        }
         Ok(())
     }
 
+    pub(crate) fn flush(&self) -> Result<(), Box<dyn Error + '_>> {
+        self.file_handle.lock()?.flush()?;
+        Ok(())
+    }
+
+    // flush if the flush interval has elapsed since the last flush
+    pub(crate) fn flush_if_due(&self) -> Result<(), Box<dyn Error + '_>> {
+        let mut handle = self.file_handle.lock()?;
+        if self.flush_policy.is_flush_due(handle.last_flush) {
+            handle.flush()?;
+        }
+        Ok(())
+    }
+
+    // periodically flush buffered records until the log writer is dropped
+    pub(crate) fn spawn_flusher(log_writer: &Arc<LogWriter>) -> io::Result<()> {
+        let interval = match log_writer.flush_policy.interval {
+            Some(interval) if log_writer.flush_policy.buffered => interval,
+            _ => return Ok(()),
+        };
+        let log_writer = Arc::downgrade(log_writer);
+        thread::Builder::new()
+            .name("logrotate-flush".to_string())
+            .spawn(move || run_flusher(log_writer, interval))?;
+        Ok(())
+    }
+
+    // block until the files rolled so far have been compressed
+    pub(crate) fn wait_for_compression(&self) {
+        if let Some(compressor) = &self.compressor {
+            compressor.wait();
+        }
+    }
+
     fn file_size(&self) -> Result<u64, Box<dyn Error + '_>> {
         Ok(self.file_handle.lock()?.size)
     }
@@ -114,35 +464,34 @@ impl Logger {
             return Ok(());
         }
         let mut handle = self.file_handle.lock()?;
-        match self.rotation_remove {
-            RotationRemove::ByCount(count) => {
-                let count = if count > 0 { count as usize - 1 } else { 0 };
-                handle.remove_file_by_count(count)?;
+        if self.is_zero_rotation_remove() {
+            handle.truncate()?;
+            handle.files.remove_old_files(&self.retention)?;
+            return Ok(());
+        }
+        match &self.compressor {
+            Some(compressor) => {
+                let now = self.timezone.now();
+                let staged_path = handle.stage()?;
+                compressor.send(staged_path, now)?;
             }
-            RotationRemove::ByMaxAge(age) => {
-                handle.remove_files_by_age(age)?;
+            None => {
+                handle.rollover()?;
+                handle.files.remove_old_files(&self.retention)?;
             }
-        };
-        if self.compress && self.delay_compress {
-            handle.compress_old_files()?;
         }
-        if !self.is_zero_rotation_remove() {
-            let compress = self.compress && !self.delay_compress;
-            handle.rollover(compress)?;
-        }
-        handle.truncate()?;
         Ok(())
     }
 
     fn is_zero_rotation_remove(&self) -> bool {
-        match self.rotation_remove {
-            RotationRemove::ByCount(0) => true,
-            _ => false,
-        }
+        self.retention
+            .rules
+            .iter()
+            .any(|rotation_remove| matches!(rotation_remove, RotationRemove::ByCount(0)))
     }
 
     fn update_next_rotation_time(&self) -> Result<(), Box<dyn Error + '_>> {
-        let next_rotation_time = self.rotation_policy.next_rotation_time();
+        let next_rotation_time = self.rotation_policy.next_rotation_time(self.timezone);
         if next_rotation_time > 0 {
             // TODO: Use try_write instead
             let mut rotation = self.next_rotation_time.write()?;
@@ -152,7 +501,7 @@ impl Logger {
     }
 
     fn should_rotate(&self) -> Result<bool, Box<dyn Error + '_>> {
-        let next_rotation_time = self.next_rotation_time.read()?.clone();
+        let next_rotation_time = *self.next_rotation_time.read()?;
         let file_size = self.file_size()?;
         if has_crossed_rotation_time(next_rotation_time) {
             self.update_next_rotation_time()?;
@@ -171,7 +520,82 @@ impl Logger {
     }
 }
 
-impl log::Log for Logger {
+fn run_flusher(log_writer: Weak<LogWriter>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let log_writer = match log_writer.upgrade() {
+            Some(log_writer) => log_writer,
+            None => break,
+        };
+        let result = log_writer.flush_if_due().map_err(|e| e.to_string());
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+impl Writer {
+    fn write_message(&self, level: Option<LogLevel>, message: Vec<u8>) -> io::Result<()> {
+        match self {
+            Self::Blocking(log_writer) => log_writer
+                .write_message(level, &message)
+                .map_err(|e| io::Error::other(e.to_string())),
+            Self::NonBlocking(worker) => worker.send(level, message),
+        }
+    }
+
+    fn flush(&self) {
+        match self {
+            Self::Blocking(log_writer) => {
+                if let Err(e) = log_writer.flush() {
+                    eprintln!("{}", e);
+                }
+            }
+            Self::NonBlocking(worker) => worker.flush(),
+        }
+    }
+
+    fn shutdown(&self) {
+        match self {
+            Self::Blocking(log_writer) => log_writer.wait_for_compression(),
+            Self::NonBlocking(worker) => worker.shutdown(),
+        }
+    }
+}
+
+impl RotatingLogger {
+    /// Returns a guard that flushes the logger, and stops the background writer in
+    /// non-blocking mode, when dropped. Useful when the logger is moved somewhere it is
+    /// never dropped, e.g. installed as the global logger.
+    pub fn guard(&self) -> LoggerGuard {
+        LoggerGuard {
+            writer: self.writer.clone(),
+        }
+    }
+
+    /// Number of records dropped so far because the non-blocking queue was full.
+    pub fn dropped(&self) -> u64 {
+        match &self.writer {
+            Writer::Blocking(_) => 0,
+            Writer::NonBlocking(worker) => worker.dropped(),
+        }
+    }
+}
+
+impl Drop for RotatingLogger {
+    fn drop(&mut self) {
+        self.writer.shutdown();
+    }
+}
+
+impl Drop for LoggerGuard {
+    fn drop(&mut self) {
+        self.writer.flush();
+        self.writer.shutdown();
+    }
+}
+
+impl log::Log for RotatingLogger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
         metadata.level() <= self.log_level
     }
@@ -179,24 +603,37 @@ impl log::Log for Logger {
         if !self.enabled(record.metadata()) {
             return;
         }
-        let file_line = match (record.file(), record.line()) {
-            (Some(f), Some(l)) => format!("{}:{}", f, l),
-            _ => String::new(),
-        };
-        let target = record.target();
-        let level = record.level();
-        let timestamp = Utc::now().format(TIMESTAMP_FORMAT);
-        let message = format!(
-            "{} {} [{}] {}: {}\n",
-            timestamp,
-            file_line,
-            target,
-            level,
-            record.args()
-        );
-        if let Err(e) = self.write_message(&message) {
+        let mut message = Vec::new();
+        let timestamp = self.timezone.now();
+        if let Err(e) = self.formatter.format(&mut message, &timestamp, record) {
+            eprintln!("{}", e);
+            return;
+        }
+        if let Err(e) = self.writer.write_message(Some(record.level()), message) {
             eprintln!("{}", e);
         }
     }
-    fn flush(&self) {}
+    fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+impl Write for RotatingLogger {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &RotatingLogger {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write_message(None, buf.to_vec())?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush();
+        Ok(())
+    }
 }
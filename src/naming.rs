@@ -0,0 +1,474 @@
+use std::{
+    cmp::Reverse,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{
+    format::{parse_and_remainder, Item, Parsed, StrftimeItems},
+    DateTime, FixedOffset, NaiveDateTime, NaiveTime,
+};
+
+use crate::{builder::Compression, utils::*};
+
+// date format used by a bare `{date}` placeholder
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// How rolled log files are named.
+#[derive(Debug, Clone, Default)]
+pub enum Naming {
+    /// The time of rotation is added to the name, e.g. `output.2024-03-02-03:22:36.log`.
+    #[default]
+    Timestamp,
+    /// Classic logrotate numbering, e.g. `output.log.1`, `output.log.2.gz`. The newest
+    /// rolled file is always `.1`; older files are shifted up on every rotation.
+    Numbered,
+    /// A user supplied template for the rolled file name, e.g. `{name}-{date:%Y%m%d}.{ext}`.
+    ///
+    /// Supported placeholders are `{name}` and `{ext}` for the parts of the log file name,
+    /// `{date}` or `{date:FORMAT}` for the strftime formatted rotation time, `{index}` for a
+    /// numbered suffix that is shifted like `Naming::Numbered` and `{compress}` for the
+    /// compression suffix. The template must contain `{name}` and at least one of `{date}`
+    /// and `{index}`; `{compress}` may only appear at the end and is implied otherwise.
+    /// Templates are validated when the logger is built.
+    Template(String),
+}
+
+// rolled file naming resolved for one log file
+#[derive(Debug, Clone)]
+pub(crate) enum NameScheme {
+    Timestamp,
+    Numbered,
+    Template(Template),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Date(String),
+    Index,
+    Compress,
+}
+
+// a parsed `Naming::Template` with `{name}` and `{ext}` already substituted
+#[derive(Debug, Clone)]
+pub(crate) struct Template {
+    segments: Vec<Segment>,
+}
+
+// a rolled file of the log file with the values parsed from its name
+#[derive(Debug, Default)]
+pub(crate) struct RolledFile {
+    pub(crate) path: PathBuf,
+    // the rotation time in the name, as wall clock time of the logger's timezone
+    pub(crate) time: Option<NaiveDateTime>,
+    // the `{date}` of a template name as it is written
+    date: String,
+    index: u32,
+    // sequence number added to a name that was already taken
+    seq: u32,
+    // extension of a compressed file
+    pub(crate) compressed: Option<&'static str>,
+}
+
+impl Naming {
+    pub(crate) fn scheme(&self, file_name: &str, file_extn: &str) -> Result<NameScheme, String> {
+        let scheme = match self {
+            Naming::Timestamp => NameScheme::Timestamp,
+            Naming::Numbered => NameScheme::Numbered,
+            Naming::Template(template) => {
+                let template = Template::new(template, file_name, file_extn)
+                    .map_err(|e| format!("invalid naming template {:?}: {}", template, e))?;
+                NameScheme::Template(template)
+            }
+        };
+        Ok(scheme)
+    }
+}
+
+impl Template {
+    pub(crate) fn new(template: &str, file_name: &str, file_extn: &str) -> Result<Self, String> {
+        let mut segments = vec![];
+        let mut has_name = false;
+        let mut rest = template;
+        while !rest.is_empty() {
+            let start = rest.find(['{', '}']).unwrap_or(rest.len());
+            push_literal(&mut segments, &rest[..start]);
+            rest = &rest[start..];
+            if rest.is_empty() {
+                break;
+            }
+            if rest.starts_with('}') {
+                return Err("unmatched '}'".to_string());
+            }
+            let end = rest.find('}').ok_or("unmatched '{'")?;
+            let placeholder = &rest[1..end];
+            rest = &rest[end + 1..];
+            match placeholder.split_once(':') {
+                Some(("date", format)) => segments.push(Segment::Date(format.to_string())),
+                Some(_) => return Err(format!("unknown placeholder {{{}}}", placeholder)),
+                None => match placeholder {
+                    "name" => {
+                        has_name = true;
+                        push_literal(&mut segments, file_name);
+                    }
+                    "ext" => push_literal(&mut segments, file_extn),
+                    "date" => segments.push(Segment::Date(DEFAULT_DATE_FORMAT.to_string())),
+                    "index" => segments.push(Segment::Index),
+                    "compress" => segments.push(Segment::Compress),
+                    _ => return Err(format!("unknown placeholder {{{}}}", placeholder)),
+                },
+            }
+        }
+        let template = Self { segments };
+        template.validate(has_name, &log_file_path("", file_name, file_extn))?;
+        Ok(template)
+    }
+
+    fn validate(&self, has_name: bool, log_file: &Path) -> Result<(), String> {
+        if !has_name {
+            return Err("{name} is required".to_string());
+        }
+        let count = |f: fn(&Segment) -> bool| self.segments.iter().filter(|s| f(s)).count();
+        let dates = count(|s| matches!(s, Segment::Date(_)));
+        let indexes = count(|s| matches!(s, Segment::Index));
+        let compress = count(|s| matches!(s, Segment::Compress));
+        if dates + indexes == 0 {
+            return Err("{date} or {index} is required".to_string());
+        }
+        if dates > 1 || indexes > 1 || compress > 1 {
+            return Err("{date}, {index} and {compress} can be used only once".to_string());
+        }
+        if compress == 1 && self.segments.last() != Some(&Segment::Compress) {
+            return Err("{compress} must be at the end".to_string());
+        }
+        for pair in self.segments.windows(2) {
+            let variable = |s: &Segment| matches!(s, Segment::Date(_) | Segment::Index);
+            if variable(&pair[0]) && variable(&pair[1]) {
+                return Err("{date} and {index} must be separated".to_string());
+            }
+        }
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) if literal.contains(['/', '\\']) => {
+                    return Err("path separators are not allowed".to_string());
+                }
+                Segment::Date(format) => {
+                    let items = StrftimeItems::new(format);
+                    if format.is_empty() || items.clone().any(|item| item == Item::Error) {
+                        return Err(format!("invalid date format {:?}", format));
+                    }
+                }
+                _ => {}
+            }
+        }
+        // every generated name must be parsed back to the same values
+        let date = DateTime::parse_from_rfc3339("2024-03-02T03:22:36+00:00").unwrap();
+        let date = self.format_date(&date);
+        if date.contains(['/', '\\']) {
+            return Err("path separators are not allowed".to_string());
+        }
+        let index = if indexes > 0 { 12 } else { 0 };
+        for compressed in [None].into_iter().chain(COMPRESSED_EXTNS.map(Some)) {
+            let name = self.file_name(&date, index, compressed);
+            match self.parse(&name) {
+                Some(f) if f.date == date && f.index == index && f.compressed == compressed => {}
+                _ => return Err(format!("generated name {:?} cannot be parsed", name)),
+            }
+        }
+        if self.parse(&log_file.to_string_lossy()).is_some() {
+            return Err("rolled files cannot be told apart from the log file".to_string());
+        }
+        let staged = format!("{}.{}1", log_file.to_string_lossy(), STAGED_INFIX);
+        if self.parse(&staged).is_some() {
+            return Err("rolled files cannot be told apart from staged log files".to_string());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn has_index(&self) -> bool {
+        self.segments.contains(&Segment::Index)
+    }
+
+    pub(crate) fn format_date(&self, now: &DateTime<FixedOffset>) -> String {
+        self.segments
+            .iter()
+            .find_map(|segment| match segment {
+                Segment::Date(format) => Some(now.format(format).to_string()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn path(
+        &self,
+        dir: &str,
+        date: &str,
+        index: u32,
+        compressed: Option<&str>,
+    ) -> PathBuf {
+        let mut path = PathBuf::from(dir);
+        path.push(self.file_name(date, index, compressed));
+        path
+    }
+
+    fn file_name(&self, date: &str, index: u32, compressed: Option<&str>) -> String {
+        let mut name = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => name.push_str(literal),
+                Segment::Date(_) => name.push_str(date),
+                Segment::Index => name.push_str(&index.to_string()),
+                Segment::Compress => {}
+            }
+        }
+        if let Some(extn) = compressed {
+            name.push('.');
+            name.push_str(extn);
+        }
+        name
+    }
+
+    // parse the name of a rolled file, the path of the returned file is left empty
+    fn parse(&self, name: &str) -> Option<RolledFile> {
+        let (mut rest, compressed) = strip_compressed(name);
+        let mut file = RolledFile {
+            compressed,
+            ..Default::default()
+        };
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => rest = rest.strip_prefix(literal.as_str())?,
+                Segment::Date(format) => {
+                    let mut parsed = Parsed::new();
+                    let items = StrftimeItems::new(format);
+                    let remainder = parse_and_remainder(&mut parsed, rest, items).ok()?;
+                    file.date = rest[..rest.len() - remainder.len()].to_string();
+                    file.time = parsed_time(&parsed);
+                    // reject dates chrono accepts but never generates, e.g. `2024031`
+                    if matches!(file.time, Some(time) if time.format(format).to_string() != file.date)
+                    {
+                        return None;
+                    }
+                    rest = remainder;
+                }
+                Segment::Index => {
+                    let len = rest.bytes().take_while(u8::is_ascii_digit).count();
+                    file.index = rest[..len].parse().ok().filter(|index| *index > 0)?;
+                    rest = &rest[len..];
+                }
+                Segment::Compress => {}
+            }
+        }
+        // without `{index}` rolled files are ordered by their date, which must be valid
+        if !self.has_index() && file.time.is_none() {
+            return None;
+        }
+        // names of templates without `{index}` may end with a sequence number added when
+        // the name was already taken, see `unique_rolled_path`
+        if !rest.is_empty() {
+            let seq = rest.strip_prefix('.').filter(|_| !self.has_index())?;
+            file.seq = parse_number(seq)?;
+        }
+        Some(file)
+    }
+}
+
+impl NameScheme {
+    // whether rolled files are numbered, the newest one being 1
+    fn has_index(&self) -> bool {
+        match self {
+            NameScheme::Timestamp => false,
+            NameScheme::Numbered => true,
+            NameScheme::Template(template) => template.has_index(),
+        }
+    }
+
+    fn parse(&self, name: &str, file_name: &str, file_extn: &str) -> Option<RolledFile> {
+        match self {
+            NameScheme::Timestamp => parse_timestamp_name(name, file_name, file_extn),
+            NameScheme::Numbered => parse_numbered_name(name, file_name, file_extn),
+            NameScheme::Template(template) => template.parse(name),
+        }
+    }
+
+    // rolled files of the log file, ordered from newest to oldest by the index or the time
+    // in their names
+    pub(crate) fn rolled_files(
+        &self,
+        dir: &str,
+        depth: usize,
+        file_name: &str,
+        file_extn: &str,
+    ) -> io::Result<Vec<RolledFile>> {
+        let mut files = vec![];
+        for path in walk_dir(dir, depth)? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(file) = self.parse(&name, file_name, file_extn) {
+                files.push(RolledFile { path, ..file });
+            }
+        }
+        if self.has_index() {
+            files.sort_unstable_by_key(|file| file.index);
+        } else {
+            files.sort_unstable_by_key(|file| Reverse((file.time, file.seq)));
+        }
+        Ok(files)
+    }
+
+    // temporary files of compressed rolled files left by compressions interrupted by a
+    // crash, see `compress_file`
+    pub(crate) fn tmp_files(
+        &self,
+        dir: &str,
+        depth: usize,
+        file_name: &str,
+        file_extn: &str,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for path in walk_dir(dir, depth)? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let file = name
+                .strip_suffix(".tmp")
+                .and_then(|name| self.parse(name, file_name, file_extn));
+            if matches!(file, Some(file) if file.compressed.is_some()) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    // rename every numbered rolled file to the next index, starting from the oldest, so
+    // that index 1 becomes free for the newest rolled file
+    pub(crate) fn shift_rolled_files(
+        &self,
+        dir: &str,
+        depth: usize,
+        file_name: &str,
+        file_extn: &str,
+    ) -> io::Result<()> {
+        if !self.has_index() {
+            return Ok(());
+        }
+        let files = self.rolled_files(dir, depth, file_name, file_extn)?;
+        for file in files.iter().rev() {
+            let (index, compressed) = (file.index + 1, file.compressed);
+            let name = match self {
+                NameScheme::Template(template) => template.path("", &file.date, index, compressed),
+                _ => numbered_path("", file_name, file_extn, index, compressed),
+            };
+            fs::rename(&file.path, file.path.with_file_name(name))?;
+        }
+        Ok(())
+    }
+}
+
+fn push_literal(segments: &mut Vec<Segment>, literal: &str) {
+    if literal.is_empty() {
+        return;
+    }
+    match segments.last_mut() {
+        Some(Segment::Literal(last)) => last.push_str(literal),
+        _ => segments.push(Segment::Literal(literal.to_string())),
+    }
+}
+
+// the time of a parsed date, a missing time of day is taken as midnight and a date without
+// a day, e.g. of monthly rotation, as the first day of its month or year
+fn parsed_time(parsed: &Parsed) -> Option<NaiveDateTime> {
+    if let Ok(time) = parsed.to_naive_datetime_with_offset(0) {
+        return Some(time);
+    }
+    let mut parsed = parsed.clone();
+    let has_day = parsed.day.is_some()
+        || parsed.ordinal.is_some()
+        || parsed.week_from_sun.is_some()
+        || parsed.week_from_mon.is_some()
+        || parsed.isoweek.is_some();
+    if !has_day {
+        if parsed.month.is_none() {
+            parsed.set_month(1).ok()?;
+        }
+        parsed.set_day(1).ok()?;
+    }
+    let date = parsed.to_naive_date().ok()?;
+    Some(date.and_time(parsed.to_naive_time().unwrap_or(NaiveTime::MIN)))
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// parse `{file_name}.{timestamp}[.{seq}][.{file_extn}][.{compression_extn}]`
+fn parse_timestamp_name(name: &str, file_name: &str, file_extn: &str) -> Option<RolledFile> {
+    let (mut rest, compressed) = strip_compressed(name);
+    if !file_extn.is_empty() {
+        rest = rest.strip_suffix(file_extn)?.strip_suffix('.')?;
+    }
+    let rest = rest.strip_prefix(file_name)?.strip_prefix('.')?;
+    let (time, seq) = NaiveDateTime::parse_and_remainder(rest, FL_NM_FORMAT).ok()?;
+    if time.format(FL_NM_FORMAT).to_string() != rest[..rest.len() - seq.len()] {
+        return None;
+    }
+    let rest = seq;
+    let seq = match rest {
+        "" => 0,
+        seq => parse_number(seq.strip_prefix('.')?)?,
+    };
+    Some(RolledFile {
+        time: Some(time),
+        seq,
+        compressed,
+        ..Default::default()
+    })
+}
+
+pub(crate) fn numbered_path(
+    dir: &str,
+    file_name: &str,
+    file_extn: &str,
+    index: u32,
+    compressed: Option<&str>,
+) -> PathBuf {
+    let mut path = log_file_path(dir, file_name, file_extn).into_os_string();
+    path.push(format!(".{}", index));
+    if let Some(extn) = compressed {
+        path.push(format!(".{}", extn));
+    }
+    PathBuf::from(path)
+}
+
+// parse `{file_name}.{file_extn}.{index}[.{compression_extn}]`
+fn parse_numbered_name(name: &str, file_name: &str, file_extn: &str) -> Option<RolledFile> {
+    let log_file_name = log_file_path("", file_name, file_extn);
+    let (rest, compressed) = strip_compressed(name);
+    let rest = rest
+        .strip_prefix(log_file_name.to_str()?)?
+        .strip_prefix('.')?;
+    let index = parse_number(rest).filter(|index| *index > 0)?;
+    Some(RolledFile {
+        index,
+        compressed,
+        ..Default::default()
+    })
+}
+
+// compress the rolled files that are not compressed yet
+pub(crate) fn compress_rolled_files(
+    files: &[RolledFile],
+    compression: Compression,
+) -> io::Result<()> {
+    let extn = match compression.extension() {
+        Some(extn) => extn,
+        None => return Ok(()),
+    };
+    for file in files {
+        if file.compressed.is_none() {
+            compress_file(&file.path, &compressed_path(&file.path, extn), compression)?;
+        }
+    }
+    Ok(())
+}
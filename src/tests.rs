@@ -1,13 +1,22 @@
+#![allow(
+    clippy::bool_assert_comparison,
+    clippy::useless_format,
+    clippy::useless_vec
+)]
+
 use std::{
     fs::{read_dir, File},
     io::{Read, Write},
     path::Path,
+    time::Duration,
 };
 
 use chrono::Utc;
 
 use crate::{
-    builder::{RotationPolicy, RotationTime},
+    builder::{AgeSource, Compression, RotationPolicy, RotationRemove, RotationTime, Timezone},
+    logger::Retention,
+    naming::{compress_rolled_files, NameScheme, Template},
     utils::*,
 };
 
@@ -65,6 +74,11 @@ mod test_utils {
             }
         }
     }
+
+    pub fn log(logger: &dyn log::Log, level: log::Level, message: &str) {
+        let args = format_args!("{}", message);
+        logger.log(&log::Record::builder().args(args).level(level).build());
+    }
 }
 
 #[test]
@@ -91,50 +105,92 @@ fn test_logger_blank_file_path() {
 
 #[test]
 fn test_logger_rotation_time() {
-    let curr_ts = Utc::now().timestamp_millis();
-    let next_rotation_time = crate::builder()
-        .minutely()
-        .rotation_time
-        .next_rotation_time();
-    let target = curr_ts + MIN_AS_MILLI_SEC;
-    assert_eq!(next_rotation_time >= target, true);
-    assert_eq!(next_rotation_time < target + 1, true);
-
-    let curr_ts = Utc::now().timestamp_millis();
-    let next_rotation_time = crate::builder().hourly().rotation_time.next_rotation_time();
-    let target = curr_ts + HOUR_AS_MILLI_SEC;
-    assert_eq!(next_rotation_time >= target, true);
-    assert_eq!(next_rotation_time < target + 1, true);
-
-    let curr_ts = Utc::now().timestamp_millis();
-    let next_rotation_time = crate::builder().daily().rotation_time.next_rotation_time();
-    let target = curr_ts + DAY_AS_MILLI_SEC;
-    assert_eq!(next_rotation_time >= target, true);
-    assert_eq!(next_rotation_time < target + 1, true);
+    let ts = |s: &str| {
+        chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.3f")
+            .unwrap()
+            .and_utc()
+    };
+    // a thursday in a leap year
+    let now = ts("2024-02-29 13:45:30.500");
+    let next_rotation_time = |rt: RotationTime| rt.next_rotation_time_from(&now);
+    assert_eq!(
+        next_rotation_time(RotationTime::Minutely),
+        ts("2024-02-29 13:46:00.000").timestamp_millis()
+    );
+    assert_eq!(
+        next_rotation_time(RotationTime::Hourly),
+        ts("2024-02-29 14:00:00.000").timestamp_millis()
+    );
+    assert_eq!(
+        next_rotation_time(RotationTime::Daily),
+        ts("2024-03-01 00:00:00.000").timestamp_millis()
+    );
+    assert_eq!(
+        next_rotation_time(RotationTime::Weekly),
+        ts("2024-03-04 00:00:00.000").timestamp_millis()
+    );
+    assert_eq!(
+        next_rotation_time(RotationTime::Monthly),
+        ts("2024-03-01 00:00:00.000").timestamp_millis()
+    );
+    assert_eq!(
+        next_rotation_time(RotationTime::Yearly),
+        ts("2025-01-01 00:00:00.000").timestamp_millis()
+    );
+    assert_eq!(next_rotation_time(RotationTime::Never), 0);
 
-    let curr_ts = Utc::now().timestamp_millis();
-    let next_rotation_time = crate::builder().weekly().rotation_time.next_rotation_time();
-    let target = curr_ts + WEEK_AS_MILLI_SEC;
-    assert_eq!(next_rotation_time >= target, true);
-    assert_eq!(next_rotation_time < target + 1, true);
+    // exactly on a boundary the next one is returned
+    let now = ts("2024-12-30 00:00:00.000");
+    assert_eq!(
+        RotationTime::Daily.next_rotation_time_from(&now),
+        ts("2024-12-31 00:00:00.000").timestamp_millis()
+    );
+    assert_eq!(
+        RotationTime::Weekly.next_rotation_time_from(&now),
+        ts("2025-01-06 00:00:00.000").timestamp_millis()
+    );
+    assert_eq!(
+        RotationTime::Monthly.next_rotation_time_from(&now),
+        ts("2025-01-01 00:00:00.000").timestamp_millis()
+    );
 
     let curr_ts = Utc::now().timestamp_millis();
     let next_rotation_time = crate::builder()
-        .monthly()
+        .daily()
+        .rotation_time
+        .next_rotation_time(Timezone::Utc);
+    assert_eq!(next_rotation_time > curr_ts, true);
+    assert_eq!(next_rotation_time <= curr_ts + 24 * 3600 * 1000, true);
+    let next_rotation_time = crate::builder()
         .rotation_time
-        .next_rotation_time();
-    let target = curr_ts + MONTH_AS_MILLI_SEC;
-    assert_eq!(next_rotation_time >= target, true);
-    assert_eq!(next_rotation_time < target + 1, true);
+        .next_rotation_time(Timezone::Utc);
+    assert_eq!(next_rotation_time, 0);
+}
 
-    let curr_ts = Utc::now().timestamp_millis();
-    let next_rotation_time = crate::builder().yearly().rotation_time.next_rotation_time();
-    let target = curr_ts + YEAR_AS_MILLI_SEC;
-    assert_eq!(next_rotation_time >= target, true);
-    assert_eq!(next_rotation_time < target + 1, true);
+#[test]
+fn test_timezone() {
+    let offset = 5 * 3600 + 1800;
+    let now = Timezone::FixedOffset(offset).now();
+    assert_eq!(now.offset().local_minus_utc(), offset);
+    assert_eq!(Timezone::Utc.now().offset().local_minus_utc(), 0);
 
-    let next_rotation_time = crate::builder().rotation_time.next_rotation_time();
-    assert_eq!(next_rotation_time, 0);
+    // daily rotation happens at midnight of the configured offset
+    let now = chrono::DateTime::parse_from_rfc3339("2024-02-29T23:10:00+05:30").unwrap();
+    let expected = chrono::DateTime::parse_from_rfc3339("2024-02-29T18:30:00Z").unwrap();
+    assert_eq!(
+        RotationTime::Daily.next_rotation_time_from(&now),
+        expected.timestamp_millis()
+    );
+
+    let path = rolled_log_path("", "output", "log", None, &now);
+    assert_eq!(path.display().to_string(), "output.2024-02-29-23:10:00.log");
+
+    let r = crate::builder()
+        .file_path("invalid_timezone.log")
+        .timezone(Timezone::FixedOffset(24 * 3600))
+        .build();
+    assert_eq!(r.is_err(), true);
+    assert_eq!(Path::new("invalid_timezone.log").exists(), false);
 }
 
 #[test]
@@ -213,24 +269,25 @@ fn test_log_file_path() {
 
 #[test]
 fn test_rolled_log_path() {
-    let path = rolled_log_path("", "output", "log", false);
+    let now = Utc::now().fixed_offset();
+    let path = rolled_log_path("", "output", "log", None, &now);
     let file_name = path.file_name().unwrap().to_string_lossy().to_string();
     assert_eq!(file_name.starts_with("output"), true);
     let extn = path.extension().unwrap().to_string_lossy().to_string();
     assert_eq!(extn, "log");
-    let path = rolled_log_path("logs", "output", "log", false);
+    let path = rolled_log_path("logs", "output", "log", None, &now);
     assert_eq!(path.display().to_string().starts_with("logs"), true);
     let file_name = path.file_name().unwrap().to_string_lossy().to_string();
     assert_eq!(file_name.starts_with("output"), true);
     let extn = path.extension().unwrap().to_string_lossy().to_string();
     assert_eq!(extn, "log");
-    let path = rolled_log_path("logs", "output", "log", true);
+    let path = rolled_log_path("logs", "output", "log", Some("gz"), &now);
     assert_eq!(path.display().to_string().starts_with("logs"), true);
     let file_name = path.file_name().unwrap().to_string_lossy().to_string();
     assert_eq!(file_name.starts_with("output"), true);
     let extn = path.extension().unwrap().to_string_lossy().to_string();
     assert_eq!(extn, "gz");
-    let path = rolled_log_path("logs", "output", "", true);
+    let path = rolled_log_path("logs", "output", "", Some("gz"), &now);
     assert_eq!(path.display().to_string().starts_with("logs"), true);
     let file_name = path.file_name().unwrap().to_string_lossy().to_string();
     assert_eq!(file_name.starts_with("output"), true);
@@ -283,7 +340,7 @@ fn test_copy_file() {
         let path = "Cargo_copied.toml";
         let (_test_data_file, file) = test_utils::TestDataFile::create(path);
         let mut src = File::open("Cargo.toml").unwrap();
-        copy_file(&mut src, file, false).unwrap();
+        copy_file(&mut src, file, Compression::None).unwrap();
         assert_eq!(Path::new(path).exists(), true);
         let file = File::open(path).unwrap();
         assert_eq!(
@@ -295,7 +352,7 @@ fn test_copy_file() {
         let path = "Cargo_copied.toml.gz";
         let (_test_data_file, file) = test_utils::TestDataFile::create(path);
         let mut src = File::open("Cargo.toml").unwrap();
-        copy_file(&mut src, file, true).unwrap();
+        copy_file(&mut src, file, Compression::Gzip(DEFAULT_GZIP_LEVEL)).unwrap();
         assert_eq!(Path::new(path).exists(), true);
         let file = File::open(path).unwrap();
         assert_eq!(
@@ -308,21 +365,26 @@ fn test_copy_file() {
 
 #[test]
 fn test_max_age() {
-    assert_eq!(max_age(1), 24 * 3600);
-    assert_eq!(max_age(2), 2 * 24 * 3600);
+    assert_eq!(max_age(1), Duration::from_secs(24 * 3600));
+    assert_eq!(max_age(2), Duration::from_secs(2 * 24 * 3600));
 }
 
 #[test]
 fn test_get_file_age() {
-    let path = Path::new("src");
-    assert_eq!(get_file_age(path).unwrap(), 0);
-    let path = Path::new("Cargo.toml");
-    assert_eq!(get_file_age(path).unwrap() > 0, true);
+    for age_source in [AgeSource::FileName, AgeSource::Modified, AgeSource::Created] {
+        let path = Path::new("src");
+        assert_eq!(get_file_age(path, age_source).unwrap(), Duration::ZERO);
+        let path = Path::new("Cargo.toml");
+        assert_eq!(
+            get_file_age(path, age_source).unwrap() > Duration::ZERO,
+            true
+        );
+    }
 }
 
 #[test]
 fn test_compress_old_files() {
-    let r = compress_old_files("Cargo.toml", "Cargo", "lock");
+    let r = NameScheme::Timestamp.rolled_files("Cargo.toml", 0, "Cargo", "lock");
     assert_eq!(r.is_err(), true);
 
     let dir_path = "compress_old_files_dir";
@@ -331,10 +393,13 @@ fn test_compress_old_files() {
     {
         let _test_data_dir = test_utils::TestDataDir::create(dir_path);
         let paths = vec![
-            format!("{}/{}.{}", dir_path, file_name, file_extn),
-            format!("{}/{}1.{}", dir_path, file_name, file_extn),
-            format!("{}/{}2.{}", dir_path, file_name, file_extn),
-            format!("{}/{}3.txt", dir_path, file_name),
+            format!("{}/output.log", dir_path),
+            format!("{}/output.2024-03-02-03:22:36.log", dir_path),
+            format!("{}/output.2024-03-02-03:22:36.1.log", dir_path),
+            format!("{}/output1.log", dir_path),
+            format!("{}/output.2024-03-02-03:22:36.txt", dir_path),
+            format!("{}/output-worker.2024-03-02-03:22:36.log", dir_path),
+            format!("{}/outputs.log", dir_path),
         ];
         let inner_dir_path = format!("{}/inner_dir", dir_path);
         let _data_dir_2 = test_utils::TestDataDir::create(&inner_dir_path);
@@ -345,25 +410,35 @@ fn test_compress_old_files() {
             file.flush().unwrap();
             file_refs.push(rf);
         }
-        compress_old_files(dir_path, file_name, file_extn).unwrap();
-        let files = read_dir(dir_path)
+        let files = NameScheme::Timestamp
+            .rolled_files(dir_path, 0, file_name, file_extn)
+            .unwrap();
+        compress_rolled_files(&files, Compression::Gzip(DEFAULT_GZIP_LEVEL)).unwrap();
+        let mut files = read_dir(dir_path)
             .unwrap()
             .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
             .collect::<Vec<_>>();
-        assert_eq!(files.contains(&format!("output.log")), true);
-        assert_eq!(files.contains(&format!("output.log.gz")), false);
-        assert_eq!(files.contains(&format!("output1.log")), false);
-        assert_eq!(files.contains(&format!("output1.log.gz")), true);
-        assert_eq!(files.contains(&format!("output2.log")), false);
-        assert_eq!(files.contains(&format!("output2.log.gz")), true);
-        assert_eq!(files.contains(&format!("output3.txt")), true);
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "inner_dir",
+                "output-worker.2024-03-02-03:22:36.log",
+                "output.2024-03-02-03:22:36.1.log.gz",
+                "output.2024-03-02-03:22:36.log.gz",
+                "output.2024-03-02-03:22:36.txt",
+                "output.log",
+                "output1.log",
+                "outputs.log",
+            ]
+        );
     }
     {
         let _test_data_dir = test_utils::TestDataDir::create("tstdir2");
         let paths = vec![
             format!("tstdir2/processtestlog"),
+            format!("tstdir2/processtestlog.2024-03-02-03:22:36"),
             format!("tstdir2/processtestlog1"),
-            format!("tstdir2/processtestlog2"),
             format!("tstdir2/someothertstfile.txt"),
         ];
         let mut file_refs = vec![];
@@ -373,93 +448,125 @@ fn test_compress_old_files() {
             file.flush().unwrap();
             file_refs.push(rf);
         }
-        compress_old_files("tstdir2", "processtestlog", "").unwrap();
-        let files = read_dir("tstdir2")
+        let files = NameScheme::Timestamp
+            .rolled_files("tstdir2", 0, "processtestlog", "")
+            .unwrap();
+        compress_rolled_files(&files, Compression::Gzip(DEFAULT_GZIP_LEVEL)).unwrap();
+        let mut files = read_dir("tstdir2")
             .unwrap()
             .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
             .collect::<Vec<_>>();
-        assert_eq!(files.contains(&format!("processtestlog")), true);
-        assert_eq!(files.contains(&format!("processtestlog.gz")), false);
-        assert_eq!(files.contains(&format!("processtestlog1")), false);
-        assert_eq!(files.contains(&format!("processtestlog1.gz")), true);
-        assert_eq!(files.contains(&format!("processtestlog2")), false);
-        assert_eq!(files.contains(&format!("processtestlog2.gz")), true);
-        assert_eq!(files.contains(&format!("someothertstfile.txt")), true);
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "processtestlog",
+                "processtestlog.2024-03-02-03:22:36.gz",
+                "processtestlog1",
+                "someothertstfile.txt",
+            ]
+        );
     }
 }
 
 #[test]
 fn test_remove_file_by_count() {
-    let r = remove_file_by_count("Cargo.toml", "Cargo", "lock", 1);
+    let remove_file_by_count = |naming: NameScheme, dir, name, extn, count| {
+        let files = naming.rolled_files(dir, 0, name, extn)?;
+        let retention = Retention {
+            rules: vec![RotationRemove::ByCount(count)],
+            min_keep: 0,
+            age_source: AgeSource::FileName,
+        };
+        remove_rolled_files(&files, 0, &retention, Timezone::Utc)
+    };
+    let r = remove_file_by_count(NameScheme::Timestamp, "Cargo.toml", "Cargo", "lock", 1);
     assert_eq!(r.is_err(), true);
-    {
-        let dir_path = "remove_file_dir";
-        let file_name = "output";
-        let file_extn = "log";
-        let _test_data_dir = test_utils::TestDataDir::create(dir_path);
-        let inner_dir_path = format!("{}/inner_dir", dir_path);
-        let _data_dir_2 = test_utils::TestDataDir::create(&inner_dir_path);
-        let paths = vec![
-            format!("{}/{}.{}", dir_path, file_name, file_extn),
-            format!("{}/{}1.{}", dir_path, file_name, file_extn),
-            format!("{}/{}2.{}", dir_path, file_name, file_extn),
-            format!("{}/{}3.txt", dir_path, file_name),
-        ];
-        let mut file_refs = vec![];
-        for p in paths.iter() {
-            let (rf, mut file) = test_utils::TestDataFile::create(p);
+    let create_files = |names: &[&str]| {
+        for name in names {
+            // created in an order different from the rotation order
+            let mut file = File::create(name).unwrap();
             file.write_all("some test data\n".as_bytes()).unwrap();
-            file.flush().unwrap();
-            file_refs.push(rf);
-            std::thread::sleep(std::time::Duration::from_millis(1));
+            std::thread::sleep(std::time::Duration::from_millis(5));
         }
-        remove_file_by_count(dir_path, file_name, file_extn, 1).unwrap();
-        let files = read_dir(dir_path)
+    };
+    let remaining = |dir_path| {
+        let mut files = read_dir(dir_path)
             .unwrap()
             .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
             .collect::<Vec<_>>();
-        assert_eq!(files.len(), 4);
-        assert_eq!(files.contains(&format!("output.log")), true);
-        assert_eq!(files.contains(&format!("output1.log")), false);
-        assert_eq!(files.contains(&format!("output2.log")), true);
-        assert_eq!(files.contains(&format!("output3.txt")), true);
+        files.sort();
+        files
+    };
+    {
+        let dir_path = "remove_file_dir";
+        let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+        let inner_dir_path = format!("{}/inner_dir", dir_path);
+        let _data_dir_2 = test_utils::TestDataDir::create(&inner_dir_path);
+        create_files(&[
+            "remove_file_dir/output.2024-03-03-00:00:00.log",
+            "remove_file_dir/output.2024-03-02-03:22:36.1.log.gz",
+            "remove_file_dir/output.2024-03-01-00:00:00.log",
+            "remove_file_dir/output.2024-03-02-03:22:36.log",
+            "remove_file_dir/output.log",
+            "remove_file_dir/output1.log",
+            "remove_file_dir/output-worker.2024-03-01-00:00:00.log",
+            "remove_file_dir/application.log",
+        ]);
+        remove_file_by_count(NameScheme::Timestamp, dir_path, "output", "log", 2).unwrap();
+        assert_eq!(
+            remaining(dir_path),
+            vec![
+                "application.log",
+                "inner_dir",
+                "output-worker.2024-03-01-00:00:00.log",
+                "output.2024-03-02-03:22:36.1.log.gz",
+                "output.2024-03-03-00:00:00.log",
+                "output.log",
+                "output1.log",
+            ]
+        );
     }
     {
         let dir_path = "remove_file_dir2";
-        let file_name = "output";
-        let file_extn = "";
         let _test_data_dir = test_utils::TestDataDir::create(dir_path);
-        let paths = vec![
-            format!("{}/{}", dir_path, file_name),
-            format!("{}/{}1", dir_path, file_name),
-            format!("{}/{}2", dir_path, file_name),
-            format!("{}/{}3.txt", dir_path, file_name),
-        ];
-        let mut file_refs = vec![];
-        for p in paths.iter() {
-            let (rf, mut file) = test_utils::TestDataFile::create(p);
-            file.write_all("some test data\n".as_bytes()).unwrap();
-            file.flush().unwrap();
-            file_refs.push(rf);
-            std::thread::sleep(std::time::Duration::from_millis(1));
-        }
-        remove_file_by_count(dir_path, file_name, file_extn, 1).unwrap();
-        let files = read_dir(dir_path)
-            .unwrap()
-            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
-            .collect::<Vec<_>>();
-        assert_eq!(files.len(), 3);
-        assert_eq!(files.contains(&format!("output")), true);
-        assert_eq!(files.contains(&format!("output1")), false);
-        assert_eq!(files.contains(&format!("output2")), true);
-        assert_eq!(files.contains(&format!("output3.txt")), true);
+        create_files(&[
+            "remove_file_dir2/output.log.10",
+            "remove_file_dir2/output.log.2.gz",
+            "remove_file_dir2/output.log.1",
+            "remove_file_dir2/output.log",
+            "remove_file_dir2/output.log.x",
+            "remove_file_dir2/output.logs.3",
+        ]);
+        remove_file_by_count(NameScheme::Numbered, dir_path, "output", "log", 2).unwrap();
+        assert_eq!(
+            remaining(dir_path),
+            vec![
+                "output.log",
+                "output.log.1",
+                "output.log.2.gz",
+                "output.log.x",
+                "output.logs.3",
+            ]
+        );
     }
     {
         let dir_path = "remove_file_dir3";
-        let file_name = "output";
-        let file_extn = "";
         let _test_data_dir = test_utils::TestDataDir::create(dir_path);
-        remove_file_by_count(dir_path, file_name, file_extn, 1).unwrap();
+        let template = Template::new("{name}-{date:%Y%m%d}", "output", "").unwrap();
+        create_files(&[
+            "remove_file_dir3/output-20240302.1",
+            "remove_file_dir3/output-20240301",
+            "remove_file_dir3/output-20240302",
+            "remove_file_dir3/output",
+            "remove_file_dir3/output-2024030",
+        ]);
+        let naming = NameScheme::Template(template);
+        remove_file_by_count(naming, dir_path, "output", "", 1).unwrap();
+        assert_eq!(
+            remaining(dir_path),
+            vec!["output", "output-2024030", "output-20240302.1"]
+        );
     }
 }
 
@@ -473,8 +580,14 @@ fn test_remove_files_by_age() {
     let _data_dir_2 = test_utils::TestDataDir::create(&inner_dir_path);
     let paths = vec![
         format!("{}/{}.{}", dir_path, file_name, file_extn),
-        format!("{}/{}1.{}", dir_path, file_name, file_extn),
-        format!("{}/{}2.{}", dir_path, file_name, file_extn),
+        format!(
+            "{}/{}.2024-03-01-00:00:00.{}",
+            dir_path, file_name, file_extn
+        ),
+        format!(
+            "{}/{}.2024-03-02-00:00:00.{}",
+            dir_path, file_name, file_extn
+        ),
         format!("{}/{}3.txt", dir_path, file_name),
     ];
     let mut file_refs = vec![];
@@ -485,14 +598,1635 @@ fn test_remove_files_by_age() {
         file_refs.push(rf);
         std::thread::sleep(std::time::Duration::from_millis(1));
     }
-    remove_files_by_age(dir_path, file_name, file_extn, 1).unwrap();
+    let files = NameScheme::Timestamp
+        .rolled_files(dir_path, 0, file_name, file_extn)
+        .unwrap();
+    assert_eq!(files.len(), 2);
+    let mut retention = Retention {
+        rules: vec![RotationRemove::ByMaxAge(max_age(1))],
+        min_keep: 0,
+        age_source: AgeSource::Modified,
+    };
+    remove_rolled_files(&files, 0, &retention, Timezone::Utc).unwrap();
     let files = read_dir(dir_path)
         .unwrap()
         .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
         .collect::<Vec<_>>();
     assert_eq!(files.len(), 5);
     assert_eq!(files.contains(&format!("output.log")), true);
-    assert_eq!(files.contains(&format!("output1.log")), true);
-    assert_eq!(files.contains(&format!("output2.log")), true);
+    assert_eq!(
+        files.contains(&format!("output.2024-03-01-00:00:00.log")),
+        true
+    );
+    assert_eq!(
+        files.contains(&format!("output.2024-03-02-00:00:00.log")),
+        true
+    );
     assert_eq!(files.contains(&format!("output3.txt")), true);
+
+    // by default the age comes from the time in the file name
+    retention.age_source = AgeSource::FileName;
+    retention.min_keep = 1;
+    let files = NameScheme::Timestamp
+        .rolled_files(dir_path, 0, file_name, file_extn)
+        .unwrap();
+    remove_rolled_files(&files, 0, &retention, Timezone::Utc).unwrap();
+    let files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(files.len(), 4);
+    assert_eq!(
+        files.contains(&format!("output.2024-03-01-00:00:00.log")),
+        false
+    );
+    assert_eq!(
+        files.contains(&format!("output.2024-03-02-00:00:00.log")),
+        true
+    );
+}
+
+#[test]
+fn test_remove_files_by_sub_day_age() {
+    let dir_path = "remove_file_sub_day_age_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let now = Utc::now().naive_utc();
+    for age in [
+        chrono::Duration::seconds(30),
+        chrono::Duration::minutes(2),
+        chrono::Duration::hours(5),
+        chrono::Duration::hours(7),
+    ] {
+        let time = (now - age).format(FL_NM_FORMAT);
+        File::create(format!("{}/output.{}.log", dir_path, time)).unwrap();
+    }
+    let remove_older_than = |age: Duration| {
+        let files = NameScheme::Timestamp
+            .rolled_files(dir_path, 0, "output", "log")
+            .unwrap();
+        let retention = Retention {
+            rules: vec![RotationRemove::ByMaxAge(age)],
+            min_keep: 0,
+            age_source: AgeSource::FileName,
+        };
+        remove_rolled_files(&files, 0, &retention, Timezone::Utc).unwrap();
+        read_dir(dir_path).unwrap().count()
+    };
+
+    // hour scale expiry only removes the file rolled seven hours ago
+    assert_eq!(remove_older_than(Duration::from_secs(6 * 3600)), 3);
+    // minute scale expiry keeps the file rolled half a minute ago
+    assert_eq!(remove_older_than(Duration::from_secs(60)), 1);
+    assert_eq!(remove_older_than(Duration::from_secs(10)), 0);
+}
+
+#[test]
+fn test_build_multiple_loggers() {
+    use log::Log;
+
+    let dir_path = "build_multiple_loggers_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let first = crate::builder()
+        .file_path(&format!("{}/first.log", dir_path))
+        .max_size(64)
+        .rotation_count(3)
+        .build()
+        .unwrap();
+    let mut second = crate::builder()
+        .file_path(&format!("{}/second.log", dir_path))
+        .build()
+        .unwrap();
+    let record = log::Record::builder()
+        .args(format_args!("message for the first logger"))
+        .level(log::Level::Info)
+        .target("test")
+        .build();
+    first.log(&record);
+    first.log(&record);
+    second.write_all(b"raw bytes\n").unwrap();
+    let files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(files.len(), 3);
+    let content = std::fs::read_to_string(format!("{}/first.log", dir_path)).unwrap();
+    assert_eq!(content.lines().count(), 1);
+    let content = std::fs::read_to_string(format!("{}/second.log", dir_path)).unwrap();
+    assert_eq!(content, "raw bytes\n");
+}
+
+#[test]
+fn test_text_formatter() {
+    use crate::format::{Formatter, TextFormatter};
+
+    let timestamp = chrono::DateTime::parse_from_rfc3339("2024-02-29T23:10:00.25+05:30").unwrap();
+    let record = log::Record::builder()
+        .args(format_args!("some message"))
+        .level(log::Level::Warn)
+        .target("app")
+        .file(Some("src/main.rs"))
+        .line(Some(12))
+        .build();
+    let mut buf = Vec::new();
+    TextFormatter.format(&mut buf, &timestamp, &record).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "2024-02-29T23:10:00.250 src/main.rs:12 [app] WARN: some message\n"
+    );
+}
+
+#[test]
+fn test_custom_formatter() {
+    use log::Log;
+
+    let dir_path = "custom_formatter_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .formatter(
+            |buf: &mut Vec<u8>, _: &chrono::DateTime<_>, record: &log::Record| {
+                writeln!(buf, "{}|{}", record.level(), record.args())
+            },
+        )
+        .build()
+        .unwrap();
+    let record = log::Record::builder()
+        .args(format_args!("custom"))
+        .level(log::Level::Error)
+        .build();
+    logger.log(&record);
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content, "ERROR|custom\n");
+}
+
+#[test]
+fn test_json_formatter() {
+    use crate::format::{Formatter, JsonFormatter};
+
+    let timestamp = chrono::DateTime::parse_from_rfc3339("2024-02-29T23:10:00.25+05:30").unwrap();
+    let record = log::Record::builder()
+        .args(format_args!(
+            "quote \" backslash \\ newline \n tab \t bell \x07"
+        ))
+        .level(log::Level::Info)
+        .target("app")
+        .module_path(Some("app::server"))
+        .file(Some("src/server.rs"))
+        .line(Some(7))
+        .build();
+    let mut buf = Vec::new();
+    JsonFormatter.format(&mut buf, &timestamp, &record).unwrap();
+    let expected = concat!(
+        r#"{"timestamp":"2024-02-29T23:10:00.250+05:30","level":"INFO","target":"app","#,
+        r#""module_path":"app::server","file":"src/server.rs","line":7,"#,
+        r#""message":"quote \" backslash \\ newline \n tab \t bell \u0007""#,
+    );
+    let output = String::from_utf8(buf).unwrap();
+    assert_eq!(output.starts_with(expected), true);
+    assert_eq!(output.ends_with("}\n"), true);
+
+    let record = log::Record::builder()
+        .args(format_args!("no location"))
+        .level(log::Level::Debug)
+        .build();
+    let mut buf = Vec::new();
+    JsonFormatter.format(&mut buf, &timestamp, &record).unwrap();
+    let output = String::from_utf8(buf).unwrap();
+    assert_eq!(
+        output.contains(r#""module_path":null,"file":null,"line":null"#),
+        true
+    );
+}
+
+#[cfg(feature = "kv")]
+#[test]
+fn test_json_formatter_key_values() {
+    use crate::format::{Formatter, JsonFormatter};
+
+    let timestamp = chrono::DateTime::parse_from_rfc3339("2024-02-29T23:10:00Z").unwrap();
+    let kvs: &[(&str, log::kv::Value)] = &[
+        ("user", log::kv::Value::from("a\"b")),
+        ("id", log::kv::Value::from(42)),
+        ("ok", log::kv::Value::from(true)),
+        ("ratio", log::kv::Value::from(0.5)),
+    ];
+    let record = log::Record::builder()
+        .args(format_args!("with fields"))
+        .level(log::Level::Info)
+        .target("app")
+        .key_values(&kvs)
+        .build();
+    let mut buf = Vec::new();
+    JsonFormatter.format(&mut buf, &timestamp, &record).unwrap();
+    let output = String::from_utf8(buf).unwrap();
+    assert_eq!(
+        output.ends_with(
+            r#""message":"with fields","fields":{"user":"a\"b","id":42,"ok":true,"ratio":0.5}}
+"#
+        ),
+        true
+    );
+}
+
+#[test]
+fn test_logfmt_formatter() {
+    use crate::format::{Formatter, LogfmtFormatter};
+
+    let timestamp = chrono::DateTime::parse_from_rfc3339("2024-02-29T23:10:00Z").unwrap();
+    let record = log::Record::builder()
+        .args(format_args!("user said \"hi\""))
+        .level(log::Level::Warn)
+        .target("app")
+        .module_path(Some("app::server"))
+        .file(Some("src/server.rs"))
+        .line(Some(7))
+        .build();
+    let mut buf = Vec::new();
+    LogfmtFormatter
+        .format(&mut buf, &timestamp, &record)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "ts=2024-02-29T23:10:00.000+00:00 level=warn target=app module=app::server \
+         file=src/server.rs line=7 msg=\"user said \\\"hi\\\"\"\n"
+    );
+
+    let record = log::Record::builder()
+        .args(format_args!("single"))
+        .level(log::Level::Error)
+        .target("")
+        .build();
+    let mut buf = Vec::new();
+    LogfmtFormatter
+        .format(&mut buf, &timestamp, &record)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "ts=2024-02-29T23:10:00.000+00:00 level=error target=\"\" msg=single\n"
+    );
+}
+
+#[cfg(feature = "kv")]
+#[test]
+fn test_logfmt_formatter_key_values() {
+    use crate::format::{Formatter, LogfmtFormatter};
+
+    let timestamp = chrono::DateTime::parse_from_rfc3339("2024-02-29T23:10:00Z").unwrap();
+    let kvs: &[(&str, log::kv::Value)] = &[
+        ("path", log::kv::Value::from("/var/log/app log")),
+        ("status", log::kv::Value::from(200)),
+        ("query", log::kv::Value::from("a=b")),
+        ("bad key", log::kv::Value::from("v")),
+        ("a=b", log::kv::Value::from(1)),
+        ("\"quoted\"\n", log::kv::Value::from(true)),
+    ];
+    let record = log::Record::builder()
+        .args(format_args!("request"))
+        .level(log::Level::Info)
+        .target("http")
+        .key_values(&kvs)
+        .build();
+    let mut buf = Vec::new();
+    LogfmtFormatter
+        .format(&mut buf, &timestamp, &record)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "ts=2024-02-29T23:10:00.000+00:00 level=info target=http msg=request \
+         path=\"/var/log/app log\" status=200 query=\"a=b\" bad_key=v a_b=1 _quoted__=true\n"
+    );
+}
+
+#[test]
+fn test_non_blocking_logger() {
+    use log::Log;
+
+    let dir_path = "non_blocking_logger_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .non_blocking(16)
+        .build()
+        .unwrap();
+    std::thread::scope(|s| {
+        for t in 0..4 {
+            let logger = &logger;
+            s.spawn(move || {
+                for i in 0..25 {
+                    let message = format!("thread {} message {}", t, i);
+                    test_utils::log(logger, log::Level::Info, &message);
+                }
+            });
+        }
+    });
+    logger.flush();
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content.lines().count(), 100);
+
+    // dropping the guard writes the queued records and stops the writer thread
+    let guard = logger.guard();
+    (&logger).write_all(b"last line\n").unwrap();
+    drop(guard);
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content.lines().last(), Some("last line"));
+    assert_eq!((&logger).write_all(b"after shutdown\n").is_err(), true);
+}
+
+#[test]
+fn test_non_blocking_rotation() {
+    let dir_path = "non_blocking_rotation_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    {
+        let logger = crate::builder()
+            .file_path(&path)
+            .max_size(64)
+            .rotation_count(2)
+            .compress(true)
+            .non_blocking(4)
+            .build()
+            .unwrap();
+        for i in 0..3 {
+            let message = format!("message number {}", i);
+            test_utils::log(&logger, log::Level::Info, &message);
+        }
+    }
+    let files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(files.contains(&"output.log".to_string()), true);
+    assert_eq!(files.iter().any(|f| f.ends_with(".log.gz")), true);
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content.lines().count(), 1);
+}
+
+#[test]
+fn test_backpressure_queue() {
+    use crate::{
+        builder::Backpressure,
+        worker::{Message, Queue},
+    };
+
+    let records = |receiver: crossbeam_channel::Receiver<Message>| {
+        receiver
+            .try_iter()
+            .map(|m| match m {
+                Message::Record(_, m) => String::from_utf8(m).unwrap(),
+                _ => panic!("unexpected message"),
+            })
+            .collect::<Vec<_>>()
+    };
+    let info = Some(log::Level::Info);
+
+    let (queue, receiver) = Queue::new(2, Backpressure::DropNewest);
+    for m in ["a", "b", "c"] {
+        queue.push(info, m.as_bytes().to_vec()).unwrap();
+    }
+    assert_eq!(queue.dropped(), 1);
+    assert_eq!(records(receiver), vec!["a", "b"]);
+
+    let (queue, receiver) = Queue::new(2, Backpressure::DropOldest);
+    for m in ["a", "b", "c", "d"] {
+        queue.push(info, m.as_bytes().to_vec()).unwrap();
+    }
+    assert_eq!(queue.dropped(), 2);
+    assert_eq!(records(receiver), vec!["c", "d"]);
+
+    let (queue, receiver) = Queue::new(2, Backpressure::DropBelow(log::Level::Warn));
+    queue.push(info, b"a".to_vec()).unwrap();
+    queue.push(Some(log::Level::Error), b"b".to_vec()).unwrap();
+    queue.push(Some(log::Level::Debug), b"c".to_vec()).unwrap();
+    queue.push(info, b"d".to_vec()).unwrap();
+    assert_eq!(queue.dropped(), 2);
+    assert_eq!(records(receiver), vec!["a", "b"]);
+}
+
+#[test]
+fn test_flush_after_shutdown() {
+    let dir_path = "flush_after_shutdown_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    for backpressure in [crate::Backpressure::Block, crate::Backpressure::DropOldest] {
+        let path = format!("{}/output_{:?}.log", dir_path, backpressure);
+        let logger = crate::builder()
+            .file_path(&path)
+            .non_blocking(4)
+            .backpressure(backpressure)
+            .build()
+            .unwrap();
+        let guard = logger.guard();
+        let (done, finished) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            // the guard flushes the logger whose background writer has already stopped
+            drop(logger);
+            drop(guard);
+            let _ = done.send(());
+        });
+        let finished = finished.recv_timeout(std::time::Duration::from_secs(10));
+        assert_eq!(finished.is_ok(), true, "{:?}", backpressure);
+    }
+}
+
+#[test]
+fn test_dropped_messages_report() {
+    let dir_path = "dropped_messages_report_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .non_blocking(1)
+        .backpressure(crate::Backpressure::DropNewest)
+        .build()
+        .unwrap();
+    for i in 0..2000 {
+        test_utils::log(&logger, log::Level::Info, &format!("message {}", i));
+    }
+    drop(logger.guard());
+    let content = std::fs::read_to_string(&path).unwrap();
+    let mut written = 0;
+    let mut reported = 0;
+    for line in content.lines() {
+        if line.ends_with(" messages dropped") {
+            let count = line.rsplit(' ').nth(2).unwrap();
+            reported += count.parse::<u64>().unwrap();
+        } else {
+            written += 1;
+        }
+    }
+    assert_eq!(reported, logger.dropped());
+    assert_eq!(written + reported, 2000);
+}
+
+#[test]
+fn test_buffered_writes() {
+    use log::Log;
+
+    let dir_path = "buffered_writes_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .buffer_size(4096)
+        .flush_on(log::Level::Error)
+        .build()
+        .unwrap();
+    let content = || std::fs::read_to_string(&path).unwrap();
+    test_utils::log(&logger, log::Level::Info, "buffered");
+    assert_eq!(content(), "");
+    logger.flush();
+    assert_eq!(content().lines().count(), 1);
+    test_utils::log(&logger, log::Level::Warn, "buffered");
+    assert_eq!(content().lines().count(), 1);
+    test_utils::log(&logger, log::Level::Error, "flushed");
+    assert_eq!(content().lines().count(), 3);
+}
+
+#[test]
+fn test_buffered_writes_flush_interval() {
+    let dir_path = "buffered_writes_interval_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    for non_blocking in [false, true] {
+        let path = format!("{}/output_{}.log", dir_path, non_blocking);
+        let mut builder = crate::builder()
+            .file_path(&path)
+            .buffer_size(4096)
+            .flush_interval(std::time::Duration::from_millis(20));
+        if non_blocking {
+            builder = builder.non_blocking(16);
+        }
+        let logger = builder.build().unwrap();
+        test_utils::log(&logger, log::Level::Info, "flushed later");
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 1);
+    }
+
+    // a zero interval would keep the flusher spinning
+    let r = crate::builder()
+        .file_path(&format!("{}/zero.log", dir_path))
+        .buffer_size(4096)
+        .flush_interval(std::time::Duration::ZERO)
+        .build();
+    assert_eq!(r.is_err(), true);
+}
+
+#[test]
+fn test_buffered_writes_rotation() {
+    let dir_path = "buffered_writes_rotation_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(2)
+        .buffer_size(4096)
+        .build()
+        .unwrap();
+    test_utils::log(&logger, log::Level::Info, "first message of the first file");
+    test_utils::log(
+        &logger,
+        log::Level::Info,
+        "first message of the second file",
+    );
+    let rolled = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|p| p.display().to_string() != path)
+        .unwrap();
+    let content = std::fs::read_to_string(rolled).unwrap();
+    assert_eq!(content.contains("first message of the first file"), true);
+}
+
+#[test]
+fn test_durability() {
+    use crate::builder::Durability;
+
+    assert_eq!(Durability::None.sync_on_rotation(), false);
+    assert_eq!(Durability::OnRotation.sync_on_rotation(), true);
+    assert_eq!(Durability::Always.sync_on_rotation(), true);
+    assert_eq!(
+        Durability::OnRotation.sync_on_write(Some(log::Level::Error)),
+        false
+    );
+    assert_eq!(
+        Durability::OnError.sync_on_write(Some(log::Level::Error)),
+        true
+    );
+    assert_eq!(
+        Durability::OnError.sync_on_write(Some(log::Level::Warn)),
+        false
+    );
+    assert_eq!(Durability::OnError.sync_on_write(None), false);
+    assert_eq!(Durability::Always.sync_on_write(None), true);
+
+    let dir_path = "durability_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(2)
+        .buffer_size(4096)
+        .durability(Durability::OnError)
+        .build()
+        .unwrap();
+    let content = || std::fs::read_to_string(&path).unwrap();
+    test_utils::log(&logger, log::Level::Info, "buffered");
+    assert_eq!(content(), "");
+    test_utils::log(&logger, log::Level::Error, "synced to disk");
+    assert_eq!(content().lines().count(), 2);
+    test_utils::log(&logger, log::Level::Error, "after rotation");
+    assert_eq!(content().lines().count(), 1);
+    let files = read_dir(dir_path).unwrap().count();
+    assert_eq!(files, 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_rotation_rename_and_copytruncate() {
+    use std::os::unix::fs::MetadataExt;
+
+    let inode = |p: &Path| std::fs::metadata(p).unwrap().ino();
+    for copytruncate in [false, true] {
+        let dir_path = format!("rotation_copytruncate_{}_dir", copytruncate);
+        let _test_data_dir = test_utils::TestDataDir::create(&dir_path);
+        let path = format!("{}/output.log", dir_path);
+        let logger = crate::builder()
+            .file_path(&path)
+            .max_size(64)
+            .rotation_count(2)
+            .copytruncate(copytruncate)
+            .build()
+            .unwrap();
+        test_utils::log(&logger, log::Level::Info, "first message of the first file");
+        let original = inode(Path::new(&path));
+        test_utils::log(
+            &logger,
+            log::Level::Info,
+            "first message of the second file",
+        );
+        let rolled = read_dir(&dir_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|p| p.display().to_string() != path)
+            .unwrap();
+        let content = std::fs::read_to_string(&rolled).unwrap();
+        assert_eq!(content.contains("first message of the first file"), true);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.contains("first message of the second file"), true);
+        assert_eq!(content.lines().count(), 1);
+        if copytruncate {
+            assert_eq!(inode(Path::new(&path)), original);
+        } else {
+            assert_eq!(inode(&rolled), original);
+        }
+    }
+}
+
+#[test]
+fn test_numbered_naming() {
+    let dir_path = "numbered_naming_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(3)
+        .naming(crate::Naming::Numbered)
+        .build()
+        .unwrap();
+    for i in 0..5 {
+        let message = format!("message number {} of the numbered log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    let mut files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(
+        files,
+        vec!["output.log", "output.log.1", "output.log.2", "output.log.3"]
+    );
+    let content = std::fs::read_to_string(format!("{}/output.log.1", dir_path)).unwrap();
+    assert_eq!(content.contains("message number 3"), true);
+    let content = std::fs::read_to_string(format!("{}/output.log.3", dir_path)).unwrap();
+    assert_eq!(content.contains("message number 1"), true);
+}
+
+#[test]
+fn test_numbered_naming_delay_compress() {
+    let dir_path = "numbered_naming_delay_compress_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(4)
+        .compress(true)
+        .delay_compress(true)
+        .naming(crate::Naming::Numbered)
+        .build()
+        .unwrap();
+    for i in 0..4 {
+        let message = format!("message number {} of the numbered log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    drop(logger);
+    let mut files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            "output.log",
+            "output.log.1",
+            "output.log.2.gz",
+            "output.log.3.gz"
+        ]
+    );
+}
+
+#[test]
+fn test_naming_template() {
+    let template = Template::new("{name}-{date:%Y%m%d}.{ext}{compress}", "app", "log").unwrap();
+    let date = chrono::DateTime::parse_from_rfc3339("2024-03-02T03:22:36+00:00").unwrap();
+    let date = template.format_date(&date);
+    assert_eq!(
+        template.path("logs", &date, 1, None),
+        Path::new("logs/app-20240302.log")
+    );
+    assert_eq!(
+        template.path("logs", &date, 1, Some("gz")),
+        Path::new("logs/app-20240302.log.gz")
+    );
+    let template = Template::new("{name}.{ext}.{date}.{index}", "app", "log").unwrap();
+    assert_eq!(
+        template.path("", "20240302-032236", 3, None),
+        Path::new("app.log.20240302-032236.3")
+    );
+
+    for invalid in [
+        "{date}.{ext}",
+        "{name}.{ext}",
+        "{name}.{date}{index}",
+        "{name}.{compress}.{date}",
+        "{name}.{date}.{date}",
+        "{name}/{date}",
+        "{name}.{date:%Y/%m}",
+        "{name}.{date:%Q}",
+        "{name}.{date:}",
+        "{name}.{unknown}",
+        "{name}.{date",
+        "{name}}.{date}",
+        "{name}.{index}.gz",
+        "{name}.{date:%H%M}",
+    ] {
+        let r = Template::new(invalid, "app", "log");
+        assert_eq!(r.is_err(), true, "{}", invalid);
+    }
+    // dates of monthly and yearly rotation
+    for valid in ["{name}-{date:%Y-%m}.{ext}", "{name}.{date:%Y}.{ext}"] {
+        let r = Template::new(valid, "app", "log");
+        assert_eq!(r.is_ok(), true, "{}", valid);
+    }
+
+    let r = crate::builder()
+        .file_path("naming_template_invalid_dir/output.log")
+        .naming(crate::Naming::Template("{name}.{ext}".to_string()))
+        .build();
+    assert_eq!(r.is_err(), true);
+    let _ = std::fs::remove_dir_all("naming_template_invalid_dir");
+}
+
+#[test]
+fn test_naming_template_rotation() {
+    let dir_path = "naming_template_rotation_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(2)
+        .compress(true)
+        .naming(crate::Naming::Template(
+            "{name}_{date:%Y%m%d}_{index}.{ext}{compress}".to_string(),
+        ))
+        .build()
+        .unwrap();
+    for i in 0..4 {
+        let message = format!("message number {} of the template log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    drop(logger);
+    let date = Utc::now().format("%Y%m%d");
+    let mut files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            format!("output.log"),
+            format!("output_{}_1.log.gz", date),
+            format!("output_{}_2.log.gz", date),
+        ]
+    );
+}
+
+#[test]
+fn test_naming_template_monthly() {
+    let dir_path = "naming_template_monthly_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    // a date without a day is taken as the first day of the month
+    for name in ["output-2023-12.log", "output-2024-01.log"] {
+        File::create(format!("{}/{}", dir_path, name)).unwrap();
+    }
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(4)
+        .timezone(Timezone::Utc)
+        .naming(crate::Naming::Template(
+            "{name}-{date:%Y-%m}.{ext}".to_string(),
+        ))
+        .build()
+        .unwrap();
+    for i in 0..4 {
+        let message = format!("message number {} of the monthly log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    drop(logger);
+    let month = Utc::now().format("%Y-%m");
+    let mut files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            format!("output-2024-01.log"),
+            format!("output-{}.log", month),
+            format!("output-{}.log.1", month),
+            format!("output-{}.log.2", month),
+            format!("output.log"),
+        ]
+    );
+}
+
+#[test]
+fn test_rotation_name_collisions() {
+    let namings = [
+        ("timestamp", crate::Naming::Timestamp),
+        (
+            "template",
+            crate::Naming::Template("{name}-{date:%Y%m%d}.{ext}".to_string()),
+        ),
+    ];
+    for (kind, naming) in namings {
+        for compress in [false, true] {
+            let dir_path = format!("rotation_name_collisions_{}_{}_dir", kind, compress);
+            let _test_data_dir = test_utils::TestDataDir::create(&dir_path);
+            let path = format!("{}/output.log", dir_path);
+            let logger = crate::builder()
+                .file_path(&path)
+                .max_size(64)
+                .rotation_count(1000)
+                .compress(compress)
+                .naming(naming.clone())
+                .build()
+                .unwrap();
+            for i in 0..50 {
+                let message = format!("colliding log message number {:02}", i);
+                test_utils::log(&logger, log::Level::Info, &message);
+            }
+            drop(logger);
+            let files = read_dir(&dir_path)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>();
+            assert_eq!(files.len(), 50);
+            let mut messages = vec![];
+            for file in files {
+                let mut content = String::new();
+                if file.extension().unwrap() == "gz" {
+                    let file = File::open(&file).unwrap();
+                    flate2::read::GzDecoder::new(file)
+                        .read_to_string(&mut content)
+                        .unwrap();
+                } else {
+                    content = std::fs::read_to_string(&file).unwrap();
+                }
+                messages.extend(
+                    content
+                        .lines()
+                        .map(|line| line[line.len() - 2..].to_string()),
+                );
+            }
+            messages.sort();
+            let expected = (0..50).map(|i| format!("{:02}", i)).collect::<Vec<_>>();
+            assert_eq!(messages, expected);
+        }
+    }
+}
+
+#[test]
+fn test_olddir() {
+    let dir_path = "olddir_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let absolute = std::env::current_dir()
+        .unwrap()
+        .join(dir_path)
+        .join("absolute");
+    let olddirs = [
+        ("archive", format!("{}/archive", dir_path)),
+        (absolute.to_str().unwrap(), absolute.display().to_string()),
+    ];
+    for (olddir, archive_path) in olddirs {
+        let path = format!("{}/output.log", dir_path);
+        let logger = crate::builder()
+            .file_path(&path)
+            .max_size(64)
+            .rotation_count(2)
+            .compress(true)
+            .delay_compress(true)
+            .olddir(olddir)
+            .build()
+            .unwrap();
+        for i in 0..5 {
+            let message = format!("message number {} of the archived log", i);
+            test_utils::log(&logger, log::Level::Info, &message);
+        }
+        drop(logger);
+        let files = read_dir(&archive_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 2);
+        let compressed = files
+            .iter()
+            .filter(|path| path.extension().unwrap() == "gz")
+            .count();
+        assert_eq!(compressed, 1);
+        assert_eq!(Path::new(&path).is_file(), true);
+        std::fs::remove_file(&path).unwrap();
+    }
+    let logs = read_dir(dir_path)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().is_file())
+        .count();
+    assert_eq!(logs, 0);
+}
+
+#[test]
+fn test_olddir_copytruncate_compress() {
+    let dir_path = "olddir_copytruncate_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let mut olddirs = vec![("archive".to_string(), format!("{}/archive", dir_path))];
+    // with copytruncate the archive may be on another filesystem
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let shm = Path::new("/dev/shm");
+        let dev = |path: &Path| std::fs::metadata(path).map(|m| m.dev()).ok();
+        if shm.is_dir() && dev(shm) != dev(Path::new(dir_path)) {
+            let olddir = format!("/dev/shm/{}_{}", dir_path, std::process::id());
+            olddirs.push((olddir.clone(), olddir));
+        }
+    }
+    for (olddir, archive_path) in olddirs {
+        let _archive_dir = test_utils::TestDataDir::create(&archive_path);
+        let path = format!("{}/output.log", dir_path);
+        let logger = crate::builder()
+            .file_path(&path)
+            .max_size(64)
+            .rotation_count(2)
+            .naming(crate::Naming::Numbered)
+            .copytruncate(true)
+            .compress(true)
+            .olddir(&olddir)
+            .build()
+            .unwrap();
+        for i in 0..5 {
+            let message = format!("message number {} of the copied log", i);
+            test_utils::log(&logger, log::Level::Info, &message);
+        }
+        drop(logger);
+        let mut files = read_dir(&archive_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!["output.log.1.gz", "output.log.2.gz"]);
+        let logs = read_dir(dir_path)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_file())
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(logs, vec!["output.log"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn test_date_dirs() {
+    assert_eq!(date_dirs_depth("%Y/%m/%d"), Ok(3));
+    assert_eq!(date_dirs_depth("%F"), Ok(1));
+    assert_eq!(date_dirs_depth("%Y-%m/%d"), Ok(2));
+    for invalid in ["", "/%Y", "%Y/../%m", "./%Y", "%Q"] {
+        assert_eq!(date_dirs_depth(invalid).is_err(), true, "{}", invalid);
+    }
+
+    let dir_path = "date_dirs_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let old_dir = format!("{}/archive/2020/01/01", dir_path);
+    std::fs::create_dir_all(&old_dir).unwrap();
+    File::create(format!("{}/output.2020-01-01-00:00:00.log", old_dir)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(2)
+        .compress(true)
+        .olddir("archive")
+        .date_dirs("%Y/%m/%d")
+        .build()
+        .unwrap();
+    for i in 0..4 {
+        let message = format!("message number {} of the partitioned log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    drop(logger);
+    let today = format!("{}/archive/{}", dir_path, Utc::now().format("%Y/%m/%d"));
+    let files = read_dir(&today).unwrap().count();
+    assert_eq!(files, 2);
+    assert_eq!(
+        Path::new(&format!("{}/archive/2020", dir_path)).exists(),
+        false
+    );
+    assert_eq!(Path::new(&format!("{}/archive", dir_path)).is_dir(), true);
+}
+
+#[test]
+fn test_max_total_size() {
+    let dir_path = "max_total_size_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(100)
+        .max_total_size(300)
+        .build()
+        .unwrap();
+    for i in 0..10 {
+        let message = format!("message number {} of the size limited log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    let rolled = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|p| p.display().to_string() != path)
+        .collect::<Vec<_>>();
+    let total_size = rolled
+        .iter()
+        .map(|p| p.metadata().unwrap().len())
+        .sum::<u64>();
+    let file_size = rolled[0].metadata().unwrap().len();
+    assert_eq!(total_size <= 300, true);
+    assert_eq!(rolled.len() as u64, 300 / file_size);
+    let content = rolled
+        .iter()
+        .map(|p| std::fs::read_to_string(p).unwrap())
+        .collect::<String>();
+    assert_eq!(content.contains("message number 8"), true);
+    assert_eq!(content.contains("message number 0"), false);
+
+    // the count limit still applies when it is stricter
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .max_total_size(300)
+        .rotation_count(1)
+        .build()
+        .unwrap();
+    test_utils::log(&logger, log::Level::Info, "one more message to rotate");
+    let rolled = read_dir(dir_path).unwrap().count() - 1;
+    assert_eq!(rolled, 1);
+}
+
+#[test]
+fn test_combined_retention() {
+    let dir_path = "combined_retention_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let rolled_messages = || {
+        let mut messages = read_dir(dir_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|p| p.display().to_string() != path)
+            .map(|p| std::fs::read_to_string(p).unwrap())
+            .map(|content| content.trim_end().chars().last().unwrap())
+            .collect::<Vec<_>>();
+        messages.sort();
+        messages
+    };
+
+    // the age limit removes every file but the newest ones protected by min_keep; the
+    // files are rolled hours apart so that their ages do not depend on test timing
+    let now = Utc::now().naive_utc();
+    for (i, minutes) in [180, 120, 90].into_iter().enumerate() {
+        let time = (now - chrono::Duration::minutes(minutes)).format(FL_NM_FORMAT);
+        let message = format!("combined retention message number {}\n", i);
+        std::fs::write(format!("{}/output.{}.log", dir_path, time), message).unwrap();
+    }
+    std::fs::write(&path, "combined retention message number 3\n").unwrap();
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(32)
+        .rotation_count(10)
+        .max_age_duration(Duration::from_secs(3600))
+        .min_keep(2)
+        .timezone(Timezone::Utc)
+        .build()
+        .unwrap();
+    assert_eq!(rolled_messages(), vec!['0', '1', '2']);
+    test_utils::log(
+        &logger,
+        log::Level::Info,
+        "combined retention message number 4",
+    );
+    assert_eq!(rolled_messages(), vec!['2', '3']);
+
+    // the count limit applies along with a generous size limit
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(3)
+        .max_total_size(1024 * 1024)
+        .build()
+        .unwrap();
+    for i in 5..9 {
+        let message = format!("combined retention message number {}", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    assert_eq!(rolled_messages(), vec!['5', '6', '7']);
+
+    // min_keep wins over a stricter count
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(1)
+        .min_keep(2)
+        .build()
+        .unwrap();
+    test_utils::log(
+        &logger,
+        log::Level::Info,
+        "combined retention message number 9",
+    );
+    assert_eq!(rolled_messages(), vec!['7', '8']);
+}
+
+#[test]
+fn test_compression_codecs() {
+    let decompress = |path: &Path| {
+        let file = File::open(path).unwrap();
+        let mut reader: Box<dyn Read> = match path.extension().unwrap().to_str().unwrap() {
+            "gz" => Box::new(flate2::read::GzDecoder::new(file)),
+            #[cfg(feature = "zstd")]
+            "zst" => Box::new(zstd::Decoder::new(file).unwrap()),
+            #[cfg(feature = "xz")]
+            "xz" => Box::new(xz2::read::XzDecoder::new(file)),
+            #[cfg(feature = "bzip2")]
+            "bz2" => Box::new(bzip2::read::BzDecoder::new(file)),
+            _ => Box::new(file),
+        };
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        content
+    };
+    let codecs = [
+        ("none", Compression::None, "log"),
+        ("gzip", Compression::Gzip(9), "gz"),
+        #[cfg(feature = "zstd")]
+        ("zstd", Compression::Zstd(19), "zst"),
+        #[cfg(feature = "xz")]
+        ("xz", Compression::Xz(6), "xz"),
+        #[cfg(feature = "bzip2")]
+        ("bzip2", Compression::Bzip2(9), "bz2"),
+    ];
+    for (kind, compression, extn) in codecs {
+        for delay_compress in [false, true] {
+            let dir_path = format!("compression_codecs_{}_{}_dir", kind, delay_compress);
+            let _test_data_dir = test_utils::TestDataDir::create(&dir_path);
+            let path = format!("{}/output.log", dir_path);
+            let logger = crate::builder()
+                .file_path(&path)
+                .max_size(64)
+                .rotation_count(2)
+                .compression(compression)
+                .delay_compress(delay_compress)
+                .naming(crate::Naming::Numbered)
+                .build()
+                .unwrap();
+            for i in 0..3 {
+                let message = format!("message number {} of the compressed log", i);
+                test_utils::log(&logger, log::Level::Info, &message);
+            }
+            drop(logger);
+            // a delayed compression leaves the newest rolled file plain
+            let newest_path = match (delay_compress, extn) {
+                (true, _) | (_, "log") => format!("{}.1", path),
+                (false, extn) => format!("{}.1.{}", path, extn),
+            };
+            let oldest_path = match extn {
+                "log" => format!("{}.2", path),
+                extn => format!("{}.2.{}", path, extn),
+            };
+            let newest = decompress(Path::new(&newest_path));
+            assert!(newest.ends_with("message number 1 of the compressed log\n"));
+            let oldest = decompress(Path::new(&oldest_path));
+            assert!(oldest.ends_with("message number 0 of the compressed log\n"));
+            assert_eq!(read_dir(&dir_path).unwrap().count(), 3);
+        }
+    }
+}
+
+#[test]
+fn test_compression_validation() {
+    let dir_path = "compression_validation_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let build = |compression| {
+        crate::builder()
+            .file_path(&path)
+            .compression(compression)
+            .build()
+    };
+    assert!(build(Compression::Gzip(0)).is_ok());
+    assert!(build(Compression::Gzip(10)).is_err());
+    #[cfg(feature = "zstd")]
+    assert!(build(Compression::Zstd(23)).is_err());
+    #[cfg(feature = "xz")]
+    assert!(build(Compression::Xz(10)).is_err());
+    #[cfg(feature = "bzip2")]
+    assert!(build(Compression::Bzip2(0)).is_err());
+
+    let builder = crate::builder().file_path(&path);
+    assert_eq!(builder.compress(true).compression, Compression::Gzip(6));
+    let builder = crate::builder()
+        .file_path(&path)
+        .compression(Compression::Gzip(1));
+    assert_eq!(builder.compress(true).compression, Compression::Gzip(1));
+    let builder = crate::builder()
+        .file_path(&path)
+        .compression(Compression::Gzip(1));
+    assert_eq!(builder.compress(false).compression, Compression::None);
+}
+
+#[test]
+fn test_rolled_files_of_any_codec() {
+    assert_eq!(strip_compressed("output.log.1"), ("output.log.1", None));
+    for extn in ["gz", "zst", "xz", "bz2"] {
+        let name = format!("output.log.1.{}", extn);
+        assert_eq!(strip_compressed(&name), ("output.log.1", Some(extn)));
+    }
+    assert_eq!(
+        strip_compressed("output.log.1zst"),
+        ("output.log.1zst", None)
+    );
+
+    // files compressed before the codec was changed are still subject to retention
+    let dir_path = "rolled_files_of_any_codec_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    for name in [
+        "output.log.1",
+        "output.log.2.zst",
+        "output.log.3.gz",
+        "output.log.4.bz2",
+    ] {
+        File::create(format!("{}/{}", dir_path, name)).unwrap();
+    }
+    let files = NameScheme::Numbered
+        .rolled_files(dir_path, 0, "output", "log")
+        .unwrap();
+    let compressed = files.iter().map(|file| file.compressed).collect::<Vec<_>>();
+    assert_eq!(compressed, vec![None, Some("zst"), Some("gz"), Some("bz2")]);
+    NameScheme::Numbered
+        .shift_rolled_files(dir_path, 0, "output", "log")
+        .unwrap();
+    let mut names = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "output.log.2",
+            "output.log.3.zst",
+            "output.log.4.gz",
+            "output.log.5.bz2"
+        ]
+    );
+
+    let template = Template::new("{name}-{index}.{ext}{compress}", "app", "log").unwrap();
+    assert_eq!(
+        template.path("logs", "", 1, Some("zst")),
+        Path::new("logs/app-1.log.zst")
+    );
+}
+
+#[test]
+fn test_compress_file_through_tmp_file() {
+    let dir_path = "compress_file_through_tmp_file_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let src = Path::new(dir_path).join("output.log.1");
+    let dst = Path::new(dir_path).join("output.log.1.gz");
+    let compression = Compression::Gzip(DEFAULT_GZIP_LEVEL);
+
+    // a failed compression leaves neither the compressed nor the temporary file behind
+    assert_eq!(compress_file(&src, &dst, compression).is_err(), true);
+    assert_eq!(read_dir(dir_path).unwrap().count(), 0);
+
+    std::fs::write(&src, "some test data\n").unwrap();
+    compress_file(&src, &dst, compression).unwrap();
+    let names = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["output.log.1.gz"]);
+    let mut content = String::new();
+    flate2::read::GzDecoder::new(File::open(&dst).unwrap())
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "some test data\n");
+}
+
+#[test]
+fn test_background_compression() {
+    let dir_path = "background_compression_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(3)
+        .compress(true)
+        .build()
+        .unwrap();
+    for i in 0..10 {
+        let message = format!("message number {} of the background log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    // the compressor finishes the queued files and applies retention when dropped
+    drop(logger);
+    let files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().unwrap() == "gz")
+        .collect::<Vec<_>>();
+    assert_eq!(files.len(), 3);
+    assert_eq!(read_dir(dir_path).unwrap().count(), 4);
+    let mut messages = vec![];
+    for file in files {
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(File::open(&file).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        messages.push(content.split("number ").nth(1).unwrap()[..1].to_string());
+    }
+    messages.sort();
+    assert_eq!(messages, vec!["6", "7", "8"]);
+}
+
+#[test]
+fn test_compressed_file_age() {
+    let dir_path = "compressed_file_age_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let day = Duration::from_secs(24 * 3600);
+    let now = std::time::SystemTime::now();
+    for (i, age) in [(1, day), (2, 3 * day)] {
+        let file = File::create(format!("{}/output.log.{}", dir_path, i)).unwrap();
+        file.set_modified(now - age).unwrap();
+    }
+    let path = format!("{}/output.log", dir_path);
+    std::fs::write(&path, "a log file large enough to be rotated\n").unwrap();
+
+    // the rolled files are compressed when the logger starts and keep their rotation
+    // time, so that the age limit counts from it rather than from the compression
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(32)
+        .max_age(2)
+        .naming(crate::Naming::Numbered)
+        .compress(true)
+        .build()
+        .unwrap();
+    test_utils::log(&logger, log::Level::Info, "message after the restart");
+    drop(logger);
+    let mut files = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(
+        files,
+        vec!["output.log", "output.log.1.gz", "output.log.2.gz"]
+    );
+    let modified = std::fs::metadata(format!("{}/output.log.2.gz", dir_path))
+        .unwrap()
+        .modified()
+        .unwrap();
+    let age = now.duration_since(modified).unwrap();
+    assert_eq!(age.as_secs(), day.as_secs());
+}
+
+#[test]
+fn test_recover_compression() {
+    let dir_path = "recover_compression_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let gzip = |path: &str, content: &str| {
+        let file = File::create(format!("{}/{}", dir_path, path)).unwrap();
+        let mut src = content.as_bytes();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        std::io::copy(&mut src, &mut encoder).unwrap();
+        encoder.finish().unwrap();
+    };
+    let write = |path: &str, content: &[u8]| {
+        std::fs::write(format!("{}/{}", dir_path, path), content).unwrap();
+    };
+    let names = || {
+        let mut names = read_dir(dir_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    // interrupted while writing the temporary file
+    write("output.log.1", b"rolled file 1\n");
+    write("output.log.1.gz.tmp", b"\x1f\x8b\x08");
+    // interrupted after the rename, before the plain file was removed
+    write("output.log.2", b"rolled file 2\n");
+    gzip("output.log.2.gz", "rolled file 2\n");
+    // a truncated compressed file written without a temporary file
+    write("output.log.3", b"rolled file 3\n");
+    gzip("output.log.3.gz", "rolled file 3\n");
+    let truncated = std::fs::read(format!("{}/output.log.3.gz", dir_path)).unwrap();
+    write("output.log.3.gz", &truncated[..truncated.len() / 2]);
+    // temporary files of other logs are left alone
+    write("other.log.1.gz.tmp", b"\x1f\x8b\x08");
+
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .rotation_count(10)
+        .naming(crate::Naming::Numbered)
+        .compress(true)
+        .build()
+        .unwrap();
+    drop(logger);
+    assert_eq!(
+        names(),
+        vec![
+            "other.log.1.gz.tmp",
+            "output.log",
+            "output.log.1.gz",
+            "output.log.2.gz",
+            "output.log.3.gz"
+        ]
+    );
+    for i in 1..=3 {
+        let file = File::open(format!("{}/output.log.{}.gz", dir_path, i)).unwrap();
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(file)
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, format!("rolled file {}\n", i));
+    }
+
+    // without compression the partial files are removed and the plain files are kept
+    write("output.log.4", b"rolled file 4\n");
+    write("output.log.4.gz.tmp", b"\x1f\x8b\x08");
+    let logger = crate::builder()
+        .file_path(&path)
+        .rotation_count(10)
+        .naming(crate::Naming::Numbered)
+        .build()
+        .unwrap();
+    drop(logger);
+    assert_eq!(names().contains(&format!("output.log.4")), true);
+    assert_eq!(names().contains(&format!("output.log.4.gz.tmp")), false);
+}
+
+#[test]
+fn test_recover_staged_files() {
+    let dir_path = "recover_staged_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let write = |path: &str, content: &str| {
+        std::fs::write(format!("{}/{}", dir_path, path), content).unwrap();
+    };
+    let gunzip = |path: &str| {
+        let file = File::open(format!("{}/{}", dir_path, path)).unwrap();
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(file)
+            .read_to_string(&mut content)
+            .unwrap();
+        content
+    };
+    // log files staged before a crash, before the compressor thread renamed them
+    write("output.log.1.gz", "rolled file 1\n");
+    write("output.log.rolling-2", "staged file 2\n");
+    write("output.log.rolling-10", "staged file 10\n");
+    write("other.log.rolling-1", "other log\n");
+
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .rotation_count(10)
+        .naming(crate::Naming::Numbered)
+        .compress(true)
+        .build()
+        .unwrap();
+    drop(logger);
+    let mut names = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "other.log.rolling-1",
+            "output.log",
+            "output.log.1.gz",
+            "output.log.2.gz",
+            "output.log.3.gz"
+        ]
+    );
+    assert_eq!(gunzip("output.log.1.gz"), "staged file 10\n");
+    assert_eq!(gunzip("output.log.2.gz"), "staged file 2\n");
+
+    // a staged file is named after the time it was rotated, not the time it is recovered
+    let rotated = std::time::SystemTime::now() - Duration::from_secs(3 * 24 * 3600);
+    let staged = File::create(format!("{}/next.log.rolling-1", dir_path)).unwrap();
+    staged.set_modified(rotated).unwrap();
+    drop(staged);
+    let logger = crate::builder()
+        .file_path(&format!("{}/next.log", dir_path))
+        .rotation_count(10)
+        .timezone(Timezone::Utc)
+        .build()
+        .unwrap();
+    drop(logger);
+    let time = chrono::DateTime::<Utc>::from(rotated).format(FL_NM_FORMAT);
+    let rolled = format!("{}/next.{}.log", dir_path, time);
+    assert_eq!(Path::new(&rolled).is_file(), true);
+}
+
+#[test]
+fn test_delay_compress_leaves_one_plain_file() {
+    let plain_files = |dir_path: &str| {
+        let mut names = read_dir(dir_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| !name.ends_with(".gz") && name != "output.log")
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    // files left plain by an earlier run are compressed on start, except the newest
+    let dir_path = "delay_compress_restart_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    for name in [
+        "output.log.1",
+        "output.log.2",
+        "output.log.3",
+        "other.log.1",
+    ] {
+        std::fs::write(format!("{}/{}", dir_path, name), name).unwrap();
+    }
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(10)
+        .compress(true)
+        .delay_compress(true)
+        .naming(crate::Naming::Numbered)
+        .build()
+        .unwrap();
+    drop(logger);
+    assert_eq!(plain_files(dir_path), vec!["other.log.1", "output.log.1"]);
+    assert_eq!(Path::new(&format!("{}.2.gz", path)).is_file(), true);
+    assert_eq!(Path::new(&format!("{}.3.gz", path)).is_file(), true);
+
+    // rotations faster than compression still leave only the newest rolled file plain
+    let dir_path = "delay_compress_timestamp_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(100)
+        .compress(true)
+        .delay_compress(true)
+        .build()
+        .unwrap();
+    for i in 0..20 {
+        let message = format!("message number {:02} of the delayed log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    drop(logger);
+    let plain = plain_files(dir_path);
+    assert_eq!(plain.len(), 1);
+    let content = std::fs::read_to_string(format!("{}/{}", dir_path, plain[0])).unwrap();
+    assert_eq!(
+        content.contains("message number 18 of the delayed log"),
+        true
+    );
+    assert_eq!(read_dir(dir_path).unwrap().count(), 20);
+}
+
+#[test]
+fn test_retention_after_failed_compression() {
+    let dir_path = "retention_after_failed_compression_dir";
+    let _test_data_dir = test_utils::TestDataDir::create(dir_path);
+    // a directory in place of the temporary file makes every compression of `.1` fail
+    std::fs::create_dir(format!("{}/output.log.1.gz.tmp", dir_path)).unwrap();
+    let path = format!("{}/output.log", dir_path);
+    let logger = crate::builder()
+        .file_path(&path)
+        .max_size(64)
+        .rotation_count(1)
+        .compress(true)
+        .naming(crate::Naming::Numbered)
+        .build()
+        .unwrap();
+    for i in 0..5 {
+        let message = format!("message number {} of the failing log", i);
+        test_utils::log(&logger, log::Level::Info, &message);
+    }
+    drop(logger);
+    let mut names = read_dir(dir_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        vec!["output.log", "output.log.1", "output.log.1.gz.tmp"]
+    );
 }
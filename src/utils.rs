@@ -1,25 +1,34 @@
 use std::{
     any::Any,
     fs::{self, File, ReadDir},
-    io::{self, Seek, SeekFrom},
-    path::{Path, PathBuf},
-    time::SystemTime,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
-use chrono::Utc;
-use flate2::{write::GzEncoder, Compression};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc,
+};
+use flate2::{read::GzDecoder, write::GzEncoder};
+
+use crate::{
+    builder::{AgeSource, Compression, RotationRemove, Timezone},
+    logger::Retention,
+    naming::RolledFile,
+};
 
 pub(crate) type Size = u64;
-pub(crate) type FileAge = u32;
+pub(crate) type FileAge = Duration;
 
 pub(crate) const FL_NM_FORMAT: &str = "%Y-%m-%d-%T";
 pub(crate) const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%T%.3f";
-pub(crate) const MIN_AS_MILLI_SEC: i64 = 60 * 1000;
-pub(crate) const HOUR_AS_MILLI_SEC: i64 = 60 * MIN_AS_MILLI_SEC;
-pub(crate) const DAY_AS_MILLI_SEC: i64 = 24 * HOUR_AS_MILLI_SEC;
-pub(crate) const WEEK_AS_MILLI_SEC: i64 = 7 * DAY_AS_MILLI_SEC;
-pub(crate) const MONTH_AS_MILLI_SEC: i64 = 30 * DAY_AS_MILLI_SEC;
-pub(crate) const YEAR_AS_MILLI_SEC: i64 = 365 * DAY_AS_MILLI_SEC;
+pub(crate) const DEFAULT_GZIP_LEVEL: u32 = 6;
+// added to the log file name while a rotated log file waits for the compressor thread
+pub(crate) const STAGED_INFIX: &str = "rolling-";
+// extensions of compressed rolled files; all of them are recognized whatever the configured
+// codec, so that files compressed before the codec was changed are still rolled files
+pub(crate) const COMPRESSED_EXTNS: [&str; 4] = ["gz", "zst", "xz", "bz2"];
 
 pub(crate) fn get_size(s: &dyn Any) -> Option<Size> {
     s.downcast_ref::<Size>().cloned()
@@ -31,9 +40,9 @@ pub(crate) fn has_crossed_rotation_time(next_rotation_time: i64) -> bool {
     next_rotation_time > 0 && next_rotation_time <= curr_ts
 }
 
-fn log_file_full_name(name: &str, extn: &str) -> String {
+pub(crate) fn log_file_full_name(name: &str, extn: &str) -> String {
     if extn.is_empty() {
-        format!("{}", name)
+        name.to_string()
     } else {
         format!("{}.{}", name, extn)
     }
@@ -54,9 +63,10 @@ pub(crate) fn rolled_log_path(
     log_dir: &str,
     log_file_name: &str,
     log_file_extn: &str,
-    compress: bool,
+    compressed: Option<&str>,
+    now: &DateTime<FixedOffset>,
 ) -> PathBuf {
-    let ts = Utc::now().format(FL_NM_FORMAT);
+    let ts = now.format(FL_NM_FORMAT);
     let mut path = PathBuf::new();
     if !log_dir.is_empty() {
         path = PathBuf::from(log_dir);
@@ -67,13 +77,57 @@ pub(crate) fn rolled_log_path(
     } else {
         format!("{}.{}.{}", log_file_name, ts, log_file_extn)
     };
-    if compress {
-        file_name = format!("{}.gz", file_name);
+    if let Some(extn) = compressed {
+        file_name = format!("{}.{}", file_name, extn);
     }
     path.push(file_name);
     path
 }
 
+// make the path of a rolled file unique; when the name `{head}{tail}` is taken, e.g. by an
+// earlier rotation within the same second, a sequence number higher than that of every
+// existing file is inserted: `{head}.{seq}{tail}`
+pub(crate) fn unique_rolled_path(path: PathBuf, tail: &str) -> io::Result<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let head = name.strip_suffix(tail).unwrap_or(&name);
+    let mut last_seq = None;
+    for entry in read_dir(&dir.to_string_lossy())? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let (name, _) = strip_compressed(&name);
+        let seq = match name.strip_suffix(tail).and_then(|n| n.strip_prefix(head)) {
+            Some("") => 0,
+            Some(seq) => match seq.strip_prefix('.').map(str::parse::<u32>) {
+                Some(Ok(seq)) => seq,
+                _ => continue,
+            },
+            None => continue,
+        };
+        last_seq = last_seq.max(Some(seq));
+    }
+    match last_seq {
+        Some(seq) => Ok(dir.join(format!("{}.{}{}", head, seq + 1, tail))),
+        None => Ok(path),
+    }
+}
+
+pub(crate) fn start_of_day(date: NaiveDate) -> NaiveDateTime {
+    date.and_hms_opt(0, 0, 0).unwrap()
+}
+
+// convert a wall clock time of the given timezone into unix timestamp in millis, moving
+// forward past the gap if that wall clock time does not exist (e.g. DST transition)
+pub(crate) fn local_timestamp_millis<Tz: TimeZone>(tz: &Tz, local: &NaiveDateTime) -> i64 {
+    let mut local = *local;
+    loop {
+        if let Some(dt) = tz.from_local_datetime(&local).earliest() {
+            return dt.timestamp_millis();
+        }
+        local += chrono::Duration::minutes(15);
+    }
+}
+
 // split the given path into parent directory, file name and file extension
 pub(crate) fn split_file_path(path: &Path) -> (String, String, String) {
     let parent_dir = path
@@ -106,128 +160,177 @@ pub(crate) fn truncate_file(file: &mut File) -> std::io::Result<()> {
     Ok(())
 }
 
-pub(crate) fn copy_file(src: &mut File, mut dst: File, compress: bool) -> io::Result<()> {
-    if compress {
-        let mut encoder = GzEncoder::new(dst, Compression::default());
-        io::copy(src, &mut encoder)?;
-        encoder.finish()?;
-    } else {
-        io::copy(src, &mut dst)?;
+pub(crate) fn copy_file(src: &mut File, mut dst: File, compression: Compression) -> io::Result<()> {
+    match compression {
+        Compression::None => {
+            io::copy(src, &mut dst)?;
+        }
+        Compression::Gzip(level) => {
+            let mut encoder = GzEncoder::new(dst, flate2::Compression::new(level));
+            io::copy(src, &mut encoder)?;
+            encoder.finish()?;
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => {
+            let mut encoder = zstd::Encoder::new(dst, level)?;
+            io::copy(src, &mut encoder)?;
+            encoder.finish()?;
+        }
+        #[cfg(feature = "xz")]
+        Compression::Xz(level) => {
+            let mut encoder = xz2::write::XzEncoder::new(dst, level);
+            io::copy(src, &mut encoder)?;
+            encoder.finish()?;
+        }
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2(level) => {
+            let mut encoder = bzip2::write::BzEncoder::new(dst, bzip2::Compression::new(level));
+            io::copy(src, &mut encoder)?;
+            encoder.finish()?;
+        }
     }
 
     Ok(())
 }
 
-pub(crate) fn max_age(age: FileAge) -> u64 {
-    age as u64 * 24 * 3600
+// split the compression extension off a file name
+pub(crate) fn strip_compressed(name: &str) -> (&str, Option<&'static str>) {
+    for extn in COMPRESSED_EXTNS {
+        if let Some(name) = name.strip_suffix(extn).and_then(|n| n.strip_suffix('.')) {
+            return (name, Some(extn));
+        }
+    }
+    (name, None)
+}
+
+// path of the compressed version of the given file
+pub(crate) fn compressed_path(path: &Path, extn: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", extn));
+    PathBuf::from(path)
+}
+
+// compress the src file into dst and remove src; dst keeps the modification time of src,
+// the rotation time that file ages may be taken from. The compressed data is written to a
+// temporary file that is fsynced and renamed to dst, so that after a crash dst is either
+// complete or missing while src is still there, see `LogFiles::recover_compression`
+pub(crate) fn compress_file(src: &Path, dst: &Path, compression: Compression) -> io::Result<()> {
+    let tmp_path = tmp_path(dst);
+    let result = File::open(src).and_then(|mut src| {
+        let modified = src.metadata()?.modified()?;
+        let tmp = File::create(&tmp_path)?;
+        copy_file(&mut src, tmp, compression)?;
+        let tmp = File::options().write(true).open(&tmp_path)?;
+        tmp.set_modified(modified)?;
+        tmp.sync_all()
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, dst)?;
+    sync_dir(&dst.parent().unwrap_or(Path::new("")).to_string_lossy())?;
+    fs::remove_file(src)
+}
+
+// whether a compressed file decompresses to its end, i.e. it was completely written;
+// files of codecs that are not enabled cannot be checked and are taken as complete
+pub(crate) fn is_complete(path: &Path, extn: &str) -> io::Result<bool> {
+    let file = File::open(path)?;
+    let mut decoder: Box<dyn Read> = match extn {
+        "gz" => Box::new(GzDecoder::new(file)),
+        #[cfg(feature = "zstd")]
+        "zst" => Box::new(zstd::Decoder::new(file)?),
+        #[cfg(feature = "xz")]
+        "xz" => Box::new(xz2::read::XzDecoder::new(file)),
+        #[cfg(feature = "bzip2")]
+        "bz2" => Box::new(bzip2::read::BzDecoder::new(file)),
+        _ => return Ok(true),
+    };
+    Ok(io::copy(&mut decoder, &mut io::sink()).is_ok())
+}
+
+// path of the temporary file a file is written to before being renamed into place
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".tmp");
+    PathBuf::from(path)
+}
+
+pub(crate) fn max_age(days: u32) -> FileAge {
+    Duration::from_secs(days as u64 * 24 * 3600)
 }
 
-fn duration_since(time: SystemTime) -> u64 {
+fn duration_since(time: SystemTime) -> FileAge {
     let now = SystemTime::now();
     match now.duration_since(time) {
-        Ok(n) => n.as_secs(),
+        Ok(n) => Duration::from_secs(n.as_secs()),
         Err(e) => {
             eprintln!("{}", e);
-            0
+            Duration::ZERO
         }
     }
 }
 
-pub(crate) fn get_file_age(path: &Path) -> io::Result<u64> {
+pub(crate) fn get_file_age(path: &Path, age_source: AgeSource) -> io::Result<FileAge> {
     if !path.is_file() {
-        return Ok(0);
+        return Ok(Duration::ZERO);
     }
-    let file = File::open(path)?;
-    let created_time = file.metadata()?.created()?;
-    let duration = duration_since(created_time);
-    Ok(duration)
+    let metadata = path.metadata()?;
+    let time = match age_source {
+        AgeSource::Created => metadata.created().or_else(|_| metadata.modified())?,
+        AgeSource::FileName | AgeSource::Modified => metadata.modified()?,
+    };
+    Ok(duration_since(time))
 }
 
-pub(crate) fn remove_files_by_age(
-    dir: &str,
-    file_name: &str,
-    file_extn: &str,
-    age: FileAge,
-) -> io::Result<()> {
-    assert!(!file_name.is_empty());
-    let curr_file = log_file_full_name(file_name, file_extn);
-    for entry in read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if !path.is_file() {
-            continue;
-        }
-        let (name, extn) = file_name_and_extension(&path);
-        if name.starts_with(file_name)
-            && !name.eq(&curr_file)
-            && (extn.eq(file_extn) || extn.eq("gz"))
-            && get_file_age(&path)? > max_age(age)
-        {
-            fs::remove_file(path)?;
+// age of a rolled file in whole seconds, the precision of the times in rolled file names,
+// taken from the source chosen by `AgeSource`
+pub(crate) fn rolled_file_age(
+    file: &RolledFile,
+    age_source: AgeSource,
+    timezone: Timezone,
+) -> io::Result<FileAge> {
+    match (age_source, file.time) {
+        (AgeSource::FileName, Some(time)) => {
+            let millis = Utc::now().timestamp_millis() - timezone.timestamp_millis(&time);
+            Ok(Duration::from_secs(millis.max(0) as u64 / 1000))
         }
+        _ => get_file_age(&file.path, age_source),
     }
-    Ok(())
 }
 
-pub(crate) fn remove_file_by_count(
-    dir: &str,
-    file_name: &str,
-    file_extn: &str,
-    count: usize,
+// remove the rolled files, ordered from newest to oldest, that violate any of the rules,
+// always keeping the newest `min_keep` files
+pub(crate) fn remove_rolled_files(
+    files: &[RolledFile],
+    depth: usize,
+    retention: &Retention,
+    timezone: Timezone,
 ) -> io::Result<()> {
-    assert!(!file_name.is_empty());
-    let curr_file = log_file_full_name(file_name, file_extn);
-    let mut entries = vec![];
-    for entry in read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            let (name, extn) = file_name_and_extension(&path);
-            if name.starts_with(file_name)
-                && !name.eq(&curr_file)
-                && (extn.eq(file_extn) || extn.eq("gz"))
-            {
-                let created_time = path.metadata()?.created()?;
-                entries.push((entry, created_time));
-            }
-        }
-    }
-    if entries.len() == 0 || entries.len() < count {
-        return Ok(());
-    }
-    entries.sort_unstable_by(|a, b| a.1.cmp(&b.1));
-    for _ in 0..count {
-        entries.pop();
-    }
-    for (entry, _) in entries {
-        fs::remove_file(entry.path())?;
-    }
-    Ok(())
-}
-
-pub(crate) fn compress_old_files(dir: &str, file_name: &str, file_extn: &str) -> io::Result<()> {
-    assert!(!file_name.is_empty());
-    let curr_file = log_file_full_name(file_name, file_extn);
-    for entry in read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if !path.is_file() {
-            continue;
+    let mut total_size = 0;
+    let mut over_total_size = false;
+    for (index, file) in files.iter().enumerate() {
+        let path = &file.path;
+        let size = path.metadata()?.len();
+        let mut remove = false;
+        for rule in &retention.rules {
+            remove |= match *rule {
+                RotationRemove::ByCount(count) => index >= count as usize,
+                RotationRemove::ByMaxAge(age) => {
+                    rolled_file_age(file, retention.age_source, timezone)? > age
+                }
+                RotationRemove::ByTotalSize(max_size) => {
+                    // once the budget is exceeded every older file goes as well
+                    over_total_size |= total_size + size > max_size;
+                    over_total_size
+                }
+            };
         }
-        let (name, extn) = file_name_and_extension(&path);
-        if name.starts_with(file_name) && !name.eq(&curr_file) && extn.eq(file_extn) {
-            {
-                let compress_file = format!("{}.gz", name);
-                let mut p = path.to_path_buf();
-                p.pop();
-                p.push(compress_file);
-                let mut src = File::open(&path)?;
-                let dst = File::create(p)?;
-                let mut encoder = GzEncoder::new(dst, Compression::default());
-                io::copy(&mut src, &mut encoder)?;
-                encoder.finish()?;
-            }
-            fs::remove_file(&path)?;
+        if remove && index >= retention.min_keep {
+            remove_rolled_file(path, depth)?;
+        } else {
+            total_size += size;
         }
     }
     Ok(())
@@ -242,15 +345,61 @@ fn dir_path(dir: &str) -> io::Result<&Path> {
     Ok(dir)
 }
 
-fn read_dir(dir: &str) -> io::Result<ReadDir> {
+// fsync the directory so that created or renamed entries survive a crash
+pub(crate) fn sync_dir(dir: &str) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir_path(dir)?)?.sync_all()?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_dir(dir: &str) -> io::Result<ReadDir> {
     let dir = dir_path(dir)?;
     fs::read_dir(dir)
 }
 
-fn file_name_and_extension(path: &Path) -> (&str, &str) {
-    let name = path.file_name().unwrap_or_default();
-    let name = name.to_str().unwrap_or_default();
-    let extn = path.extension().unwrap_or_default();
-    let extn = extn.to_str().unwrap_or_default();
-    (name, extn)
+// files in the directory or, for date partitioned rolled files, in the subdirectories
+// `depth` levels below it
+pub(crate) fn walk_dir(dir: &str, depth: usize) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if depth == 0 && path.is_file() {
+            files.push(path);
+        } else if depth > 0 && path.is_dir() {
+            files.extend(walk_dir(&path.to_string_lossy(), depth - 1)?);
+        }
+    }
+    Ok(files)
+}
+
+// remove a rolled file along with the date directories it leaves empty
+pub(crate) fn remove_rolled_file(path: &Path, depth: usize) -> io::Result<()> {
+    fs::remove_file(path)?;
+    let mut dir = path.parent();
+    for _ in 0..depth {
+        match dir {
+            Some(d) if fs::remove_dir(d).is_ok() => dir = d.parent(),
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+// number of directory levels the date format creates, e.g. 3 for `%Y/%m/%d`
+pub(crate) fn date_dirs_depth(format: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid date_dirs format {:?}", format);
+    let items = StrftimeItems::new(format);
+    if format.is_empty() || items.clone().any(|item| item == Item::Error) {
+        return Err(invalid());
+    }
+    let sample = Utc::now().format_with_items(items).to_string();
+    let components = Path::new(&sample).components();
+    if components
+        .clone()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return Err(invalid());
+    }
+    Ok(components.count())
 }
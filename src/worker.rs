@@ -0,0 +1,273 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use log::Level as LogLevel;
+
+use crate::{
+    builder::{Backpressure, Timezone},
+    format::Formatter,
+    logger::LogWriter,
+};
+
+// how often the number of dropped records is written into the log
+const DROPPED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) enum Message {
+    Record(Option<LogLevel>, Vec<u8>),
+    Flush(Sender<()>),
+    Shutdown,
+}
+
+// bounded queue of records applying the backpressure policy when it is full
+#[derive(Debug)]
+pub(crate) struct Queue {
+    sender: Sender<Message>,
+    // only kept for `Backpressure::DropOldest`, to pop the oldest record; released when
+    // the queue is closed so that the channel disconnects
+    receiver: Mutex<Option<Receiver<Message>>>,
+    backpressure: Backpressure,
+    dropped: Arc<AtomicU64>,
+    stopped: AtomicBool,
+}
+
+// background thread owning the log file; records are sent to it over a bounded channel
+#[derive(Debug)]
+pub(crate) struct Worker {
+    queue: Queue,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+// state owned by the background thread
+struct Context {
+    log_writer: LogWriter,
+    formatter: Arc<dyn Formatter>,
+    timezone: Timezone,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Queue {
+    pub(crate) fn new(capacity: usize, backpressure: Backpressure) -> (Self, Receiver<Message>) {
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let queue = Self {
+            sender,
+            receiver: Mutex::new(match backpressure {
+                Backpressure::DropOldest => Some(receiver.clone()),
+                _ => None,
+            }),
+            backpressure,
+            dropped: Arc::new(AtomicU64::new(0)),
+            stopped: AtomicBool::new(false),
+        };
+        (queue, receiver)
+    }
+
+    pub(crate) fn push(&self, level: Option<LogLevel>, message: Vec<u8>) -> io::Result<()> {
+        if self.stopped.load(Ordering::Acquire) {
+            return Err(stopped_error());
+        }
+        let message = Message::Record(level, message);
+        match self.backpressure {
+            Backpressure::Block => self.send(message),
+            Backpressure::DropNewest => self.try_send(message),
+            Backpressure::DropBelow(min_level) => match level {
+                Some(level) if level > min_level => self.try_send(message),
+                _ => self.send(message),
+            },
+            Backpressure::DropOldest => self.send_dropping_oldest(message),
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // refuse new records and release the retained receiver, so that messages sent after
+    // the background thread has stopped are discarded instead of waiting to be read
+    fn close(&self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Ok(mut receiver) = self.receiver.lock() {
+            receiver.take();
+        }
+    }
+
+    fn send(&self, message: Message) -> io::Result<()> {
+        self.sender.send(message).map_err(|_| stopped_error())
+    }
+
+    fn try_send(&self, message: Message) -> io::Result<()> {
+        match self.sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(stopped_error()),
+        }
+    }
+
+    fn send_dropping_oldest(&self, mut message: Message) -> io::Result<()> {
+        let receiver = match self.receiver.lock() {
+            Ok(receiver) => receiver.clone(),
+            Err(_) => None,
+        };
+        let receiver = match receiver {
+            Some(receiver) => receiver,
+            None => return self.send(message),
+        };
+        loop {
+            match self.sender.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(m)) => message = m,
+                Err(TrySendError::Disconnected(_)) => return Err(stopped_error()),
+            }
+            match receiver.try_recv() {
+                Ok(Message::Record(..)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                // never drop flush or shutdown requests, queue them again instead
+                Ok(control) => self.send(control)?,
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+impl Worker {
+    pub(crate) fn spawn(
+        log_writer: LogWriter,
+        capacity: usize,
+        backpressure: Backpressure,
+        formatter: Arc<dyn Formatter>,
+        timezone: Timezone,
+    ) -> io::Result<Self> {
+        let (queue, receiver) = Queue::new(capacity, backpressure);
+        let context = Context {
+            log_writer,
+            formatter,
+            timezone,
+            dropped: queue.dropped.clone(),
+        };
+        let handle = thread::Builder::new()
+            .name("logrotate-writer".to_string())
+            .spawn(move || context.run(receiver))?;
+        Ok(Self {
+            queue,
+            handle: Mutex::new(Some(handle)),
+        })
+    }
+
+    pub(crate) fn send(&self, level: Option<LogLevel>, message: Vec<u8>) -> io::Result<()> {
+        self.queue.push(level, message)
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.queue.dropped()
+    }
+
+    // block until every record queued before this call has been written
+    pub(crate) fn flush(&self) {
+        if self.queue.stopped.load(Ordering::Acquire) {
+            return;
+        }
+        let (ack, done) = crossbeam_channel::bounded(1);
+        if self.queue.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+
+    // write all queued records and stop the background thread
+    pub(crate) fn shutdown(&self) {
+        let handle = match self.handle.lock() {
+            Ok(mut handle) => handle.take(),
+            Err(_) => None,
+        };
+        if let Some(handle) = handle {
+            let _ = self.queue.send(Message::Shutdown);
+            let _ = handle.join();
+            self.queue.close();
+        }
+    }
+}
+
+impl Context {
+    fn run(self, receiver: Receiver<Message>) {
+        let mut reported = 0;
+        let mut last_report = Instant::now();
+        let timeout = match self.log_writer.flush_policy.interval {
+            Some(interval) => interval.min(DROPPED_REPORT_INTERVAL),
+            None => DROPPED_REPORT_INTERVAL,
+        };
+        loop {
+            let message = match receiver.recv_timeout(timeout) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let shutdown = matches!(message, Some(Message::Shutdown));
+            if shutdown || last_report.elapsed() >= DROPPED_REPORT_INTERVAL {
+                reported = self.report_dropped(reported);
+                last_report = Instant::now();
+            }
+            match message {
+                Some(Message::Record(level, message)) => self.write_message(level, &message),
+                Some(Message::Flush(ack)) => {
+                    self.flush();
+                    let _ = ack.send(());
+                }
+                Some(Message::Shutdown) => {
+                    self.flush();
+                    break;
+                }
+                None => {}
+            }
+            if let Err(e) = self.log_writer.flush_if_due() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    fn write_message(&self, level: Option<LogLevel>, message: &[u8]) {
+        if let Err(e) = self.log_writer.write_message(level, message) {
+            eprintln!("{}", e);
+        }
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.log_writer.flush() {
+            eprintln!("{}", e);
+        }
+    }
+
+    // write a synthetic record with the number of records dropped since the last report
+    fn report_dropped(&self, reported: u64) -> u64 {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped == reported {
+            return reported;
+        }
+        let args = format_args!("{} messages dropped", dropped - reported);
+        let record = log::Record::builder()
+            .args(args)
+            .level(LogLevel::Warn)
+            .target("logrotate")
+            .build();
+        let mut message = Vec::new();
+        let timestamp = self.timezone.now();
+        match self.formatter.format(&mut message, &timestamp, &record) {
+            Ok(()) => self.write_message(Some(LogLevel::Warn), &message),
+            Err(e) => eprintln!("{}", e),
+        }
+        dropped
+    }
+}
+
+fn stopped_error() -> io::Error {
+    io::Error::other("background writer has stopped")
+}